@@ -1,19 +1,79 @@
 use json::JsonValue;
-use std::thread;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use urbit_http_api::{default_cli_ship_interface_setup, Node, NodeContents, ShipInterface};
+use tokio::sync::watch;
+use tokio::task;
+use tokio::time::{sleep, timeout};
+use urbit_http_api::{default_cli_ship_interface_setup, ship_interface_from_local_config, Channel, Node, NodeContents, ShipInterface, UrbitAPIError};
 pub use urbit_http_api::{AuthoredMessage, Message};
 
+use crate::invites::{Invite, InviteAction, Invites};
+use crate::outbox::{unix_now, NextSend, Outbox};
+use crate::processed::ProcessedMessages;
+use crate::state_store::{Membership, StateStore, StoreError};
+use crate::storage;
+use crate::ship_events::{Backoff, EventChannel, EventError, REQUEST_TIMEOUT};
+use crate::welcome::Welcome;
+use crate::workers::{Reply, Responder, WorkerPool, WorkerPoolConfig};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ShipChat {
     pub(crate) ship_name: String,
     pub(crate) chat_name: String,
 }
 
+/// Settings for how the bot runs, as opposed to where it keeps its state.
+#[derive(Clone, Debug)]
+pub struct ChatbotConfig {
+    pub workers: WorkerPoolConfig,
+    /// Delay before reconnecting after the connection to the ship drops.
+    pub reconnect: Backoff,
+    /// Message posted in every chat the bot joins, see `Welcome`.
+    pub welcome: Option<String>,
+}
+
+/// A chat to join, from a metadata update.
+#[derive(Debug, PartialEq)]
+struct ChatJoin {
+    chat: ShipChat,
+    /// The chat's group, as `~ship/group-name`.
+    group: Option<String>,
+    /// Whether the chat was just added or its group just joined, rather than
+    /// listed in the associations sent when subscribing. Only those are welcomed.
+    added: bool,
+}
+
 struct MessagePayload {
     message: NodeContents,
     ship_chat: ShipChat,
 }
 
+/// A message from one of the bot's subscriptions, waiting to be handled.
+#[derive(Debug, PartialEq)]
+enum InboundEvent {
+    Invite(String),
+    Metadata(String),
+    Graph(String),
+    Group(String),
+    Storage(String),
+    Wallet(String),
+}
+
+/// Run a blocking request to the ship on the blocking thread pool, giving up
+/// on it after `REQUEST_TIMEOUT`.
+async fn blocking_request<T, F>(request: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, UrbitAPIError> + Send + 'static,
+{
+    match timeout(REQUEST_TIMEOUT, task::spawn_blocking(request)).await {
+        Ok(Ok(result)) => result.map_err(|e| e.to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("the ship did not respond in time".to_string()),
+    }
+}
+
 /// This struct represents a chatbot that is connected to a given `ship`,
 /// is watching/posting to a specific `chat_ship`/`chat_name`
 /// and is using the function `respond_to_message` to process any messages
@@ -21,25 +81,66 @@ struct MessagePayload {
 pub struct Chatbot {
     /// `respond_to_message` is a function defined by the user of this framework.
     /// This function receives any messages that get posted to the connected chat,
-    /// and if the function returns `Some(message)`, then `message` is posted to the
+    /// along with the chat they were posted in, and if the function returns `Some(message)`, then `message` is posted to the
     /// chat as a response. If it returns `None`, then no message is posted.
-    respond_to_message: fn(AuthoredMessage) -> Option<Message>,
+    /// It is run on the worker threads in `workers`, so slow replies do not
+    /// hold up other chats.
+    workers: WorkerPool,
+    /// Replies waiting to be posted, retried until they go through.
+    outbox: Arc<Outbox>,
+    /// Delay before reconnecting after the connection to the ship drops.
+    reconnect: Backoff,
+    /// Groups and chats the bot is in, kept across restarts.
+    store: Arc<StateStore>,
+    /// Messages already handled, so replayed messages are not answered twice.
+    processed: ProcessedMessages,
+    /// Which invites to accept, and the ones waiting for an admin.
+    invites: Arc<Invites>,
+    /// Greets the chats the bot joins.
+    welcome: Arc<Welcome>,
+    /// URL of the event channel of the last connection, deleted once the
+    /// bot has reconnected.
+    last_channel: Option<String>,
     ship: ShipInterface,
     ship_chats: Vec<ShipChat>,
 }
 
+impl ShipChat {
+    /// The chat written as `~ship/chat-name`, as used in config files.
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.ship_name, self.chat_name)
+    }
+
+    /// Whether this is a direct message chat: the `dm-inbox` graph, or a
+    /// `dm--ship` chat made by older versions of Landscape.
+    pub fn is_dm(&self) -> bool {
+        self.chat_name == "dm-inbox" || self.chat_name.starts_with("dm--")
+    }
+}
+
 impl Chatbot {
     /// Create a new `Chatbot` with a manually provided `ShipInterface`
     pub fn new(
-        respond_to_message: fn(AuthoredMessage) -> Option<Message>,
+        respond_to_message: Responder,
         ship: ShipInterface,
         ship_chats: Vec<ShipChat>,
-    ) -> Self {
-        Chatbot {
-            respond_to_message: respond_to_message,
-            ship: ship,
-            ship_chats: ship_chats,
-        }
+        config: ChatbotConfig,
+        outbox: Arc<Outbox>,
+        store: Arc<StateStore>,
+        invites: Arc<Invites>,
+    ) -> Result<Self, StoreError> {
+        Ok(Chatbot {
+            workers: WorkerPool::new(config.workers, respond_to_message),
+            outbox,
+            reconnect: config.reconnect,
+            processed: ProcessedMessages::new(store.tree("processed_messages")?),
+            welcome: Arc::new(Welcome::new(config.welcome, &store)?),
+            store,
+            invites,
+            last_channel: None,
+            ship,
+            ship_chats,
+        })
     }
 
     /// Create a new `Chatbot` with a `ShipInterface` derived automatically
@@ -47,116 +148,288 @@ impl Chatbot {
     /// `Chatbot` will create the config file, exit, and prompt the user to
     /// fill it out.
     pub fn new_with_local_config(
-        respond_to_message: fn(AuthoredMessage) -> Option<Message>,
+        respond_to_message: Responder,
         ship_chats: Vec<ShipChat>,
-    ) -> Self {
+        config: ChatbotConfig,
+        outbox: Arc<Outbox>,
+        store: Arc<StateStore>,
+        invites: Arc<Invites>,
+    ) -> Result<Self, StoreError> {
         let ship = default_cli_ship_interface_setup();
-        Self::new(respond_to_message, ship, ship_chats)
+        Self::new(respond_to_message, ship, ship_chats, config, outbox, store, invites)
     }
  
-    /// Run the `Chatbot`
-    pub fn run(&self) -> Option<()> {
+    /// Run the `Chatbot`. Reconnects whenever the connection to the ship is
+    /// lost, so this only returns if the async runtime cannot be started.
+    pub fn run(&mut self) -> Option<()> {
+        let runtime = tokio::runtime::Runtime::new().ok()?;
+        runtime.block_on(self.supervise());
+        drop(runtime);
+        self.outbox.flush();
+        Some(())
+    }
+
+    /// Keep the bot connected, logging in again and recreating the channel
+    /// and its subscriptions with a growing delay whenever the connection drops.
+    async fn supervise(&mut self) {
         println!("=======================================\nChatbot Powered By The Urbit Chatbot Framework\n=======================================");
+        // Replies are sent one at a time, so they reach each chat in order
+        let (ships, current_ship) = watch::channel(self.ship.clone());
+        tokio::spawn(Self::send_messages(current_ship, Arc::clone(&self.outbox)));
+
+        let mut backoff = self.reconnect.clone();
+        loop {
+            let error = self.run_events(&mut backoff).await;
+            println!("Lost the connection to the ship: {}", error);
+            let delay = backoff.next_delay();
+            println!("Reconnecting in {}s.", delay.as_secs());
+
+            // Keep collecting replies from the workers while waiting
+            let wait = sleep(delay);
+            tokio::pin!(wait);
+            loop {
+                tokio::select! {
+                    _ = &mut wait => break,
+                    Some(reply) = self.workers.next_reply() => self.queue_reply(reply),
+                }
+            }
+
+            // Log in again, as a restarted ship no longer knows our session
+            match task::spawn_blocking(ship_interface_from_local_config).await {
+                Ok(Some(ship)) => {
+                    self.ship = ship.clone();
+                    ships.send(ship).ok();
+                }
+                _ => println!("Failed to log in to the ship using the information in `ship_config.yaml`."),
+            }
+        }
+    }
+
+    /// Open a channel with all of the bot's subscriptions.
+    async fn connect(&self) -> Result<EventChannel, EventError> {
         // Create a `Subscription`
-        let channel = &mut self.ship.create_channel().ok()?;
-        let metadata_channel = &mut self.ship.create_channel().ok()?;
-        let invite_channel = &mut self.ship.create_channel().ok()?;
-        let settings_channel = &mut self.ship.create_channel().ok()?;
-        let btc_channel = &mut self.ship.create_channel().ok()?;
+        let mut channel = EventChannel::open(&self.ship).await?;
 
         // make channell
         // init wallet? oh duh just use /all and btc-wallet
         // get tx -hist hopefully
         // process payment hist
 
-        channel.create_new_subscription("graph-store", "/updates").ok()?;
-        metadata_channel.create_new_subscription("metadata-store", "/all").ok()?;
-        invite_channel.create_new_subscription("invite-store", "/updates").ok()?;
-        settings_channel.create_new_subscription("settings-store", "/all").ok()?;
-        btc_channel.create_new_subscription("btc-wallet", "/all").ok()?;
-
-        // Infinitely watch for new updates
-        loop {
-            channel.parse_event_messages();
-            metadata_channel.parse_event_messages();
-            invite_channel.parse_event_messages();
-            btc_channel.parse_event_messages();
+        channel.subscribe("graph-store", "/updates").await?;
+        channel.subscribe("metadata-store", "/all").await?;
+        channel.subscribe("invite-store", "/updates").await?;
+        channel.subscribe("group-store", "/groups").await?;
+        channel.subscribe("s3-store", "/all").await?;
+        channel.subscribe("btc-wallet", "/all").await?;
+        Ok(channel)
+    }
 
-            let mut messages_to_send = vec![];
-            let mut chats_to_join: Vec<ShipChat> = Vec::new();
+    /// Handle events from the ship as they arrive, until the connection is lost.
+    async fn run_events(&mut self, backoff: &mut Backoff) -> EventError {
+        let channel = &mut match self.connect().await {
+            Ok(channel) => channel,
+            Err(e) => return e,
+        };
+        println!("Connected to ~{}.", self.ship.ship_name);
+        backoff.reset();
+        if let Some(url) = self.last_channel.replace(channel.url().to_string()) {
+            if let Err(e) = channel.delete_channel(&url).await {
+                println!("Failed to delete the previous channel: {}", e);
+            }
+        }
 
-            let graph_updates = &mut channel.find_subscription("graph-store", "/updates")?;
-            let invite_updates = &mut invite_channel.find_subscription("invite-store", "/updates")?;
-            let metadata_updates = &mut metadata_channel.find_subscription("metadata-store", "/all")?;
-            let btc_updates = &mut btc_channel.find_subscription("btc-wallet", "/all")?;
+        // Wait for new updates, for a worker to finish a reply or for an admin to approve an invite
+        loop {
+            tokio::select! {
+                reply = self.workers.next_reply() => {
+                    if let Some(reply) = reply {
+                        self.queue_reply(reply);
+                    }
+                }
+                _ = self.invites.approved() => {
+                    for invite in self.invites.take_approved() {
+                        self.accept_invite(invite);
+                    }
+                }
+                events = channel.wait_for_events() => {
+                    if let Err(e) = events {
+                        return e;
+                    }
+                }
+            }
+            // Outside the select, so a reply arriving meanwhile cannot cut it short
+            if let Err(e) = channel.sync().await {
+                return e;
+            }
+            self.handle_events(channel);
+        }
+    }
 
-            // Read all of the current SSE messages to find if any are for the chat
-            // we are looking for.
-            loop {
-                let pop_invite = invite_updates.pop_message();
-                let pop_metadata = metadata_updates.pop_message();
-                let pop_message = graph_updates.pop_message();
-                let btc_message = btc_updates.pop_message();
+    /// Handle every update waiting in the subscriptions of `channel`.
+    fn handle_events(&mut self, channel: &mut EventChannel) {
+        let mut messages_to_send = vec![];
+        let mut chats_to_join = Vec::new();
+        let mut chats_to_leave = Vec::new();
 
-                println!("{:?}", btc_message);
+        // Handle every SSE message that arrived, in the order they were read
+        let events = Self::collect_events(|app, path| channel.pop_message(app, path));
+        for event in events {
+            match event {
                 // Process invitations to new groups
-                if let Some(invite) = &pop_invite {
-                    let invite_result = self.invite_accept(invite);
-                    match invite_result {
-                        Ok(true) => println!("Successfully accepted invite."),
-                        Ok(false) => (), // Ignore when invite-store sends a message that confirms we accepted the invite
-                        Err(e) => println!("There was an error accepting the invite: {}", e)
-                    }
+                InboundEvent::Invite(invite) => {
+                    // Ignore when invite-store sends a message that confirms we accepted the invite
+                    self.invite_accept(&invite);
                 }
-                // Get any newly created chats in our groups
-                if let Some(metadata) = &pop_metadata {
-                    chats_to_join = self.get_chats_to_join(metadata);
+                // Get any newly created or removed chats in our groups
+                InboundEvent::Metadata(metadata) => {
+                    chats_to_join.extend(self.get_chats_to_join(&metadata));
+                    chats_to_leave.extend(self.get_chats_to_leave(&metadata));
                 }
                 // Process new messages, determine if we should reply
-                if let Some(message) = &pop_message {
-                    messages_to_send = self.get_messages_to_send(message);
-                }
-                // If no messages left, stop
-                // TODO should we only break if all three channels have no messages left?
-                if matches!(&pop_message, None) && matches!(&pop_invite, None) && matches!(&pop_metadata, None) {
-                    break;
+                InboundEvent::Graph(message) => messages_to_send.extend(self.get_messages_to_send(&message)),
+                // Notice when we are kicked from a group
+                InboundEvent::Group(update) => chats_to_leave.extend(self.get_group_chats_to_leave(&update)),
+                // Keep the S3 settings from Landscape for the `ship` storage backend
+                InboundEvent::Storage(update) => storage::record_ship_s3_update(&self.store, &update),
+                // Payments are logged as they are recorded, the updates are too noisy to print
+                InboundEvent::Wallet(update) => self.invites.record_payments(&update),
+            }
+        }
+
+        // Join newly added chats
+        for ChatJoin { chat, group, added } in chats_to_join {
+            println!("Attempting to join {} {}", chat.ship_name, chat.chat_name);
+            self.record_chat(&chat, group.clone(), Membership::Joining);
+            let ship = self.ship.clone();
+            let store = Arc::clone(&self.store);
+            let outbox = Arc::clone(&self.outbox);
+            let welcome = Arc::clone(&self.welcome);
+            tokio::spawn(async move {
+                let bot = ship.ship_name.clone();
+                let spider_data = Self::build_chat_join_json(chat.ship_name.clone(), chat.chat_name.clone());
+                let spider = blocking_request(move || {
+                    ship.spider("landscape", "json", "graph-view-action/graph-join", &spider_data)
+                });
+                let status = match spider.await {
+                    Ok(_) => {
+                        println!("Actually joined chat {} on ship {}", chat.chat_name, chat.ship_name);
+                        if added {
+                            if let Some(message) = welcome.message_for(&chat, group.as_deref(), &bot) {
+                                outbox.push(chat.clone(), message);
+                            }
+                        }
+                        Membership::Joined
+                    }
+                    Err(e) => {
+                        println!("Failed to join chat {} on ship {}: {}", chat.chat_name, chat.ship_name, e);
+                        Membership::Failed
+                    }
+                };
+                // A leave that came in meanwhile wins
+                if let Err(e) = store.finish_join(&chat, status) {
+                    println!("Failed to save the state of {}: {}", chat.key(), e);
                 }
+            });
+        }
+
+        // Leave removed chats and chats of groups we were kicked from
+        for chat in chats_to_leave {
+            self.leave_chat(chat);
+        }
+
+        // Send the notices returned while queueing messages; replies from the
+        // workers are sent as soon as they are ready.
+        for message in messages_to_send {
+            self.outbox.push(message.ship_chat, message.message);
+        }
+    }
+
+    // Takes every message waiting in the subscriptions, one from each in turn,
+    // until all of them are empty.
+    fn collect_events(mut pop_message: impl FnMut(&str, &str) -> Option<String>) -> VecDeque<InboundEvent> {
+        let mut events = VecDeque::new();
+        loop {
+            let popped = vec![
+                pop_message("invite-store", "/updates").map(InboundEvent::Invite),
+                pop_message("metadata-store", "/all").map(InboundEvent::Metadata),
+                pop_message("graph-store", "/updates").map(InboundEvent::Graph),
+                pop_message("group-store", "/groups").map(InboundEvent::Group),
+                pop_message("s3-store", "/all").map(InboundEvent::Storage),
+                pop_message("btc-wallet", "/all").map(InboundEvent::Wallet),
+            ];
+            // If no messages left, stop
+            if popped.iter().all(Option::is_none) {
+                return events;
             }
+            events.extend(popped.into_iter().flatten());
+        }
+    }
 
-            // Join newly added chats
-            for chat in chats_to_join.iter() {
-                println!("Attempting to join {} {}", chat.ship_name, chat.chat_name);
-                let spider_data = self.build_chat_join_json(chat.ship_name.clone(), chat.chat_name.clone());
-                let spider = channel.spider(
-                    "landscape",
-                    "json",
-                    "graph-view-action/graph-join",
-                    &spider_data,
-                );
-                thread::sleep(Duration::new(0, 500000000));
+    // Posts replies from the outbox as they become due, reusing one channel for all of them.
+    async fn send_messages(current_ship: watch::Receiver<ShipInterface>, outbox: Arc<Outbox>) {
+        let channel: Arc<Mutex<Option<Channel>>> = Arc::new(Mutex::new(None));
+        loop {
+            let entry = match outbox.next(unix_now()) {
+                NextSend::Send(entry) => entry,
+                NextSend::WaitUntil(time) => {
+                    let delay = Duration::from_secs(time.saturating_sub(unix_now()));
+                    tokio::select! {
+                        _ = sleep(delay) => (),
+                        _ = outbox.changed() => (),
+                    }
+                    continue;
+                }
+                NextSend::Empty => {
+                    outbox.changed().await;
+                    continue;
+                }
+            };
 
-                if let Ok(spider_response) = spider {
-                    println!("Actually joined chat {} on ship {}", chat.chat_name, chat.ship_name);
+            let (ship, channel) = (current_ship.borrow().clone(), Arc::clone(&channel));
+            let (chat, message) = (entry.chat.clone(), entry.message.clone());
+            let sent = blocking_request(move || {
+                let mut channel = channel.lock().unwrap();
+                // Open a new channel after logging in again
+                let logged_in = |channel: &Channel| channel.ship_interface.session_auth == ship.session_auth;
+                if !channel.as_ref().is_some_and(logged_in) {
+                    if let Some(old) = channel.take() {
+                        old.delete_channel();
+                    }
+                    *channel = Some(ship.create_channel()?);
+                }
+                let sent = channel.as_mut().unwrap().chat().send_chat_message(
+                    &chat.ship_name,
+                    &chat.chat_name,
+                    &message,
+                );
+                // Start over with a fresh channel in case this one is broken
+                if sent.is_err() {
+                    if let Some(old) = channel.take() {
+                        old.delete_channel();
+                    }
                 }
+                sent
+            });
+            match sent.await {
+                Ok(_) => outbox.sent(entry.id),
+                Err(e) => outbox.failed_attempt(entry.id, &e, unix_now()),
             }
+        }
+    }
 
-            // Send each response message that was returned by the `respond_to_message`
-            // function. This is separated until after done parsing messages due to mutable borrows.
-            for message in messages_to_send {
-                channel
-                    .chat()
-                    .send_chat_message(
-                        &message.ship_chat.ship_name,
-                        &message.ship_chat.chat_name,
-                        &message.message,
-                    )
-                    .ok();
+    /// Queue a worker's reply, then record its message as handled.
+    fn queue_reply(&self, reply: Reply) {
+        match reply.message {
+            Some(message) => {
+                self.outbox.push(reply.chat.clone(), message);
+                self.processed.handled(&reply.chat, &reply.index);
             }
-            thread::sleep(Duration::new(0, 500000000));
+            None => self.processed.ignored(&reply.chat, &reply.index),
         }
     }
 
-    // Returns the bot's reply to a message if the message is a command.
+    // Queues a message for the workers. Replies come back through
+    // `self.workers`; only a notice that the chat is busy is returned here.
     fn get_messages_to_send(&self, message: &str) -> Vec<MessagePayload> {
         let mut messages_to_send = vec![];
         // Parse it to json
@@ -169,6 +442,10 @@ impl Chatbot {
                 if node.author == self.ship.ship_name {
                     return messages_to_send;
                 }
+                // Ignore messages replayed after a reconnect and ones sent before the bot started
+                if !self.processed.claim(&origin_ship_chat, &node.index, node.time_sent) {
+                    return messages_to_send;
+                }
 
                 // Else parse it as an `AuthoredMessage`
                 let authored_message = AuthoredMessage::new(
@@ -177,15 +454,15 @@ impl Chatbot {
                     &node.time_sent_formatted(),
                     &node.index,
                 );
-                // If the Chatbot intends to respond to the provided message
-                if let Some(message) = (self.respond_to_message)(authored_message) {
-                    println!("Replied to message.");
+                // Hand the message to a worker, or tell the chat to slow down
+                if let Err(e) = self.workers.submit(origin_ship_chat.clone(), authored_message) {
+                    println!("Dropped message in {}: {}", origin_ship_chat.key(), e);
+                    // Answered by the notice, which is queued with the other messages to send
+                    self.processed.handled(&origin_ship_chat, &node.index);
                     messages_to_send.push(MessagePayload {
-                        message: message,
+                        message: Message::new().add_text("Too many requests in this chat, please try again in a moment."),
                         ship_chat: origin_ship_chat,
                     });
-                } else {
-                    println!("Message ignored.")
                 }
             }
         }
@@ -193,36 +470,67 @@ impl Chatbot {
     }
    
 
-    // Accept an invite from a third party ship/chat
-    // Return Ok(true) if invite was accepted
-    // Return Ok(false) if we got a message from invite-store that wasn't necessarily the invite (this happens sometimes)
-    pub fn invite_accept(&self, invite_message: &str) -> Result<bool, urbit_http_api::UrbitAPIError> {
+    // Accept an invite from a third party ship/chat in the background, as the invite policy decides
+    // Return true if an invite was accepted
+    // Return false if it was ignored or held, or if we got a message from invite-store that wasn't
+    // necessarily the invite (this happens sometimes)
+    pub fn invite_accept(&self, invite_message: &str) -> bool {
         let invite_message_json = json::parse(invite_message).unwrap();
-        if invite_message_json["invite-update"]["invite"].is_null() {
-            return Ok(false);
-        }
-        let poke_channel = &mut self.ship.create_channel().unwrap();
-        let ship = invite_message_json["invite-update"]["invite"]["invite"]["resource"]["ship"].clone().to_string();
-        let name = invite_message_json["invite-update"]["invite"]["invite"]["resource"]["name"].clone().to_string();
-        println!("Got an invite from group {} on ship {}. Raw JSON: {}", name, ship, invite_message_json);
-        let poke = poke_channel.poke(
-            "group-view",
-            "group-view-action",
-            &self.build_invite_accept_json(ship, name)
-        );
-        thread::sleep(Duration::new(0, 500000000));
-        match poke {
-            Ok(_) => Ok(true),
-            Err(e) => Err(e)
+        let invite = match Invite::from_update(&invite_message_json) {
+            Some(invite) => invite,
+            None => return false,
+        };
+        println!("Got an invite to {} from {}. Raw JSON: {}", invite.group(), invite.inviter, invite_message_json);
+        match self.invites.decide(&invite) {
+            InviteAction::Accept => {
+                self.accept_invite(invite);
+                true
+            }
+            InviteAction::Ignore => {
+                println!("Ignoring the invite to {}, as the invite policy does not allow it.", invite.group());
+                false
+            }
+            InviteAction::Hold => {
+                match self.invites.hold(&invite) {
+                    Ok(id) => println!("Holding the invite to {} as #{}. Admins can accept it with `approve {}`.", invite.group(), id, id),
+                    Err(e) => println!("Failed to hold the invite to {}: {}", invite.group(), e),
+                }
+                false
+            }
         }
     }
 
-    pub fn build_invite_accept_json(&self, ship: String, name: String) -> JsonValue {
+    // Joins the group of an invite. Approved invites that fail are held again.
+    fn accept_invite(&self, invite: Invite) {
+        println!("Accepting the invite to {}.", invite.group());
+        let ship_interface = self.ship.clone();
+        let invites = Arc::clone(&self.invites);
+        tokio::spawn(async move {
+            let data = Self::build_invite_accept_json(invite.host.trim_start_matches('~').to_string(), invite.name.clone());
+            let poke = blocking_request(move || {
+                let mut poke_channel = ship_interface.create_channel()?;
+                let poked = poke_channel.poke("group-view", "group-view-action", &data);
+                poke_channel.delete_channel();
+                poked
+            });
+            match poke.await {
+                Ok(_) => println!("Successfully accepted invite."),
+                Err(e) => {
+                    println!("There was an error accepting the invite: {}", e);
+                    if invites.decide(&invite) == InviteAction::Hold {
+                        invites.hold(&invite).ok();
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn build_invite_accept_json(ship: String, name: String) -> JsonValue {
         let mut json_object = JsonValue::new_object();
         json_object["join"] = JsonValue::new_object();
         json_object["join"]["resource"] = JsonValue::new_object();
         json_object["join"]["resource"]["ship"] = JsonValue::String(format!("~{}", ship.clone()));
-        json_object["join"]["resource"]["name"] = JsonValue::String(name.clone().into());
+        json_object["join"]["resource"]["name"] = JsonValue::String(name.clone());
         json_object["join"]["ship"] = JsonValue::String(format!("~{}", ship.clone()));
         json_object["join"]["app"] = JsonValue::String("groups".to_string());
         json_object["join"]["autojoin"] = JsonValue::Boolean(true);
@@ -230,66 +538,195 @@ impl Chatbot {
         json_object
     }
 
-    pub fn build_chat_join_json(&self, ship: String, chat: String) -> JsonValue {
+    pub fn build_chat_leave_json(ship: String, chat: String) -> JsonValue {
+        let mut json_object = JsonValue::new_object();
+        json_object["leave"] = JsonValue::new_object();
+        json_object["leave"]["resource"] = JsonValue::new_object();
+        json_object["leave"]["resource"]["ship"] = JsonValue::String(ship);
+        json_object["leave"]["resource"]["name"] = JsonValue::String(chat);
+        json_object
+    }
+
+    pub fn build_chat_join_json(ship: String, chat: String) -> JsonValue {
         let mut json_object = JsonValue::new_object();
         json_object["join"] = JsonValue::new_object();
         json_object["join"] = JsonValue::new_object();
         json_object["join"]["resource"] = JsonValue::new_object();
-        json_object["join"]["resource"]["ship"] = JsonValue::String(ship.clone().into());
-        json_object["join"]["resource"]["name"] = JsonValue::String(chat.clone().into());
-        json_object["join"]["ship"] = JsonValue::String(ship.clone().into());
+        json_object["join"]["resource"]["ship"] = JsonValue::String(ship.clone());
+        json_object["join"]["resource"]["name"] = JsonValue::String(chat.clone());
+        json_object["join"]["ship"] = JsonValue::String(ship.clone());
         json_object
     }
 
-    // Assembles list of chats to join.
-    pub fn get_chats_to_join(&self, metadata_update: &str) -> Vec<ShipChat> {
-        let mut chats_to_join: Vec<ShipChat> = Vec::new();
+    // Assembles list of chats to join, along with the group each one is in.
+    // Chats the bot has already joined are skipped.
+    fn get_chats_to_join(&self, metadata_update: &str) -> Vec<ChatJoin> {
         let update_result: serde_json::Value = serde_json::from_str(metadata_update).unwrap();
-        // Reacts when new chats are created
-        if let Some(new_chat_update) = update_result["metadata-update"]["add"].as_object() {
-            if new_chat_update["app-name"] == "graph" && new_chat_update["resource"].is_string() {
-                let chat = self.chat_id_from_resource(new_chat_update["resource"].as_str().unwrap());
-                println!("Joined Chat: {}", chat.chat_name);
-                chats_to_join.push(chat);
+        let mut chats_to_join = Vec::new();
+        for join in Self::chats_in_metadata(&update_result) {
+            // Chats the bot was already in when it subscribed are never
+            // welcomed, even if the state store does not know them yet
+            if !join.added {
+                self.welcome.mark_welcomed(&join.chat);
+            }
+            if self.store.should_join(&join.chat) {
+                println!("{} Chat: {}", if join.added { "Joined" } else { "In" }, join.chat.chat_name);
+                chats_to_join.push(join);
             }
         }
-        // Handles the case where new chats are created while the bot is offline.
-        // This does not scale. A later version will not attempt to rejoin chats the bot already knows about, just new ones.
-        if let Some(associations_update) = update_result["metadata-update"]["associations"].as_object() {
-            for (_, value) in associations_update {
-                if value["app-name"] == "graph" {
-                    let chat = self.chat_id_from_resource(value["resource"].as_str().unwrap());
-                    println!("In Chat: {}", chat.chat_name);
-                    chats_to_join.push(chat);
+        chats_to_join
+    }
+
+    // The graph chats in a metadata update: a newly created chat in an `add`,
+    // the chats of a group the bot just joined in an `initial-group`, or every
+    // chat in the `associations` sent when subscribing, which covers chats
+    // created while the bot was offline.
+    fn chats_in_metadata(update: &serde_json::Value) -> Vec<ChatJoin> {
+        let group_of = |update: &serde_json::Value| update["group"].as_str().map(|group| Self::chat_id_from_resource(group).key());
+        let mut chats = Vec::new();
+        let added = &update["metadata-update"]["add"];
+        if added["app-name"] == "graph" {
+            if let Some(resource) = added["resource"].as_str() {
+                chats.push(ChatJoin {
+                    chat: Self::chat_id_from_resource(resource),
+                    group: group_of(added),
+                    added: true,
+                });
+            }
+        }
+        let lists = [
+            (&update["metadata-update"]["initial-group"]["associations"], true),
+            (&update["metadata-update"]["associations"], false),
+        ];
+        for (associations, added) in lists {
+            for value in associations.as_object().into_iter().flat_map(|map| map.values()) {
+                if let (Some(resource), true) = (value["resource"].as_str(), value["app-name"] == "graph") {
+                    chats.push(ChatJoin {
+                        chat: Self::chat_id_from_resource(resource),
+                        group: group_of(value),
+                        added,
+                    });
                 }
             }
         }
-        // TODO: remove chat from our persistent store
-        if let Some(removed_from_group_update) = update_result["metadata-update"]["remove"].as_object() {
-            println!("Removed from Chat: {:?}", removed_from_group_update);
+        chats
+    }
+
+    // Returns the chat removed by a metadata update, if it is one the bot is in.
+    pub fn get_chats_to_leave(&self, metadata_update: &str) -> Vec<ShipChat> {
+        let update_result: serde_json::Value = serde_json::from_str(metadata_update).unwrap();
+        let removed = Self::removed_chat(&update_result).filter(|chat| !self.store.should_join(chat));
+        if let Some(chat) = &removed {
+            println!("Removed from Chat: {}", chat.chat_name);
+        }
+        removed.into_iter().collect()
+    }
+
+    // Returns the chats to leave when a group-store update says we were kicked
+    // from a group or the group was deleted.
+    pub fn get_group_chats_to_leave(&self, group_update: &str) -> Vec<ShipChat> {
+        let update_result: serde_json::Value = match serde_json::from_str(group_update) {
+            Ok(update) => update,
+            Err(_) => return vec![],
+        };
+        let group = match Self::group_left(&update_result, &self.ship.ship_name) {
+            Some(group) => group,
+            None => return vec![],
+        };
+        println!("Removed from group {}", group);
+        self.store
+            .chats_in_group(&group)
+            .into_iter()
+            .filter(|record| record.status != Membership::Left)
+            .map(|record| record.chat)
+            .collect()
+    }
+
+    // The graph chat removed by a metadata `remove` update.
+    fn removed_chat(update: &serde_json::Value) -> Option<ShipChat> {
+        let removed = &update["metadata-update"]["remove"];
+        // Older ships send the resource as a path, newer ones wrap it together with its app
+        let (app_name, resource) = match &removed["resource"] {
+            serde_json::Value::String(resource) => (&removed["app-name"], resource.as_str()),
+            wrapped => (&wrapped["app-name"], wrapped["resource"].as_str()?),
+        };
+        if app_name != "graph" {
+            return None;
+        }
+        Some(Self::chat_id_from_resource(resource))
+    }
+
+    // The group, as `~ship/group-name`, that `our_ship` was kicked from or that was deleted.
+    fn group_left(update: &serde_json::Value, our_ship: &str) -> Option<String> {
+        let update = &update["groupUpdate"];
+        let group_key = |resource: &serde_json::Value| {
+            let ship = resource["ship"].as_str()?.trim_start_matches('~');
+            Some(format!("~{}/{}", ship, resource["name"].as_str()?))
+        };
+        if update["removeGroup"].is_object() {
+            return group_key(&update["removeGroup"]["resource"]);
+        }
+        let removed = &update["removeMembers"];
+        let kicked = removed["ships"]
+            .as_array()?
+            .iter()
+            .any(|ship| ship.as_str().map(|ship| ship.trim_start_matches('~')) == Some(our_ship));
+        if kicked {
+            group_key(&removed["resource"])
+        } else {
+            None
+        }
+    }
+
+    // Forgets a chat: drops its queued commands and replies, then leaves its graph.
+    fn leave_chat(&self, chat: ShipChat) {
+        println!("Leaving chat {} on ship {}", chat.chat_name, chat.ship_name);
+        let commands = self.workers.cancel(&chat);
+        let replies = self.outbox.cancel(&chat);
+        if commands + replies > 0 {
+            println!("Dropped {} queued commands and {} replies for {}", commands, replies, chat.key());
+        }
+        self.record_chat(&chat, None, Membership::Left);
+
+        let ship = self.ship.clone();
+        tokio::spawn(async move {
+            let spider_data = Self::build_chat_leave_json(chat.ship_name.clone(), chat.chat_name.clone());
+            let spider = blocking_request(move || {
+                ship.spider("landscape", "json", "graph-view-action/graph-leave", &spider_data)
+            });
+            if let Err(e) = spider.await {
+                println!("Failed to leave chat {} on ship {}: {}", chat.chat_name, chat.ship_name, e);
+            }
+        });
+    }
+
+    // Saves the bot's membership of a chat, logging rather than failing on errors.
+    fn record_chat(&self, chat: &ShipChat, group: Option<String>, status: Membership) {
+        if let Err(e) = self.store.set_chat(chat, group, status) {
+            println!("Failed to save the state of {}: {}", chat.key(), e);
         }
-        chats_to_join
     }
 
     fn get_ship_chat_from_resource_json(&self, resource_json: &JsonValue) -> ShipChat {
         let resource = resource_json["graph-update"]["add-nodes"]["resource"].clone();
-        return ShipChat {
+        ShipChat {
             ship_name: format!("~{}", resource["ship"]),
             chat_name: format!("{}", resource["name"]),
-        };
+        }
     }
 
-    fn chat_id_from_resource(&self, resource: &str) -> ShipChat {
+    fn chat_id_from_resource(resource: &str) -> ShipChat {
         let splitted_value = resource.split("/");
-        return ShipChat {
+        ShipChat {
             ship_name: splitted_value.clone().collect::<Vec<&str>>()[2].to_string(),
             chat_name: splitted_value.clone().last().unwrap().to_string(),
-        };
+        }
     }
 
     /// Deprecated: Urbit Alpha responds to all commands in all chats of which it is a member.
     /// Checks whether the resource json matches one of the chat_name & chat_ship pairs
     /// that this `Chatbot` is interacting with
+    #[allow(dead_code)]
     fn check_resource_json(&self, resource_json: &JsonValue) -> bool {
         let resource = resource_json["graph-update"]["add-nodes"]["resource"].clone();
         let chat_name = format!("{}", resource["name"]);
@@ -308,19 +745,79 @@ impl Chatbot {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-
-    fn respond_to_message(authored_message: AuthoredMessage) -> Option<Message> {
-        None
-    }
+    use crate::testing::chat;
 
     #[test]
     fn test_build_invite_accept_json() {
-        let shipchats: Vec<ShipChat> = Vec::new();
-        let bot = Chatbot::new_with_local_config(respond_to_message, shipchats);
-        let mut jsonObj = bot.build_invite_accept_json("dozzod-dozzod".to_string(), "groupname".to_string());
+        let json_obj = Chatbot::build_invite_accept_json("dozzod-dozzod".to_string(), "groupname".to_string());
         // JSON for invite accept needs ~ in front of ship name
-        assert_eq!(Some(jsonObj["join"]["resource"]["ship"].as_str().unwrap().get(0..1)), "~");
+        assert_eq!(json_obj["join"]["resource"]["ship"].as_str().unwrap().get(0..1), Some("~"));
         // assert_eq!(jsonObj["join"]["ship"].as_str().unwrap().get(0..1), "~");
     }
 
+    #[test]
+    fn test_only_added_chats_are_welcomed() {
+        let add = r#"{"metadata-update": {"add": {"app-name": "graph", "group": "/ship/~zod/g", "resource": "/ship/~zod/chat-1"}}}"#;
+        assert_eq!(
+            Chatbot::chats_in_metadata(&serde_json::from_str(add).unwrap()),
+            vec![ChatJoin {
+                chat: chat("chat-1"),
+                group: Some("~zod/g".to_string()),
+                added: true
+            }]
+        );
+        let associations = r#"{"metadata-update": {"associations": {
+            "/ship/~zod/chat-2": {"app-name": "graph", "resource": "/ship/~zod/chat-2"},
+            "/ship/~zod/g": {"app-name": "groups", "resource": "/ship/~zod/g"}}}}"#;
+        assert_eq!(
+            Chatbot::chats_in_metadata(&serde_json::from_str(associations).unwrap()),
+            vec![ChatJoin {
+                chat: chat("chat-2"),
+                group: None,
+                added: false
+            }]
+        );
+        // Sent after accepting an invite
+        let joined = r#"{"metadata-update": {"initial-group": {"group": "/ship/~zod/g", "associations": {
+            "/ship/~zod/chat-3": {"app-name": "graph", "group": "/ship/~zod/g", "resource": "/ship/~zod/chat-3"}}}}}"#;
+        assert!(Chatbot::chats_in_metadata(&serde_json::from_str(joined).unwrap())[0].added);
+    }
+
+    #[test]
+    fn test_removed_chat() {
+        let old = r#"{"metadata-update": {"remove": {"app-name": "graph", "resource": "/ship/~zod/chat-1"}}}"#;
+        let new = r#"{"metadata-update": {"remove": {"group": "/ship/~zod/g", "resource": {"app-name": "graph", "resource": "/ship/~zod/chat-1"}}}}"#;
+        let expected = chat("chat-1");
+        assert_eq!(Chatbot::removed_chat(&serde_json::from_str(old).unwrap()), Some(expected.clone()));
+        assert_eq!(Chatbot::removed_chat(&serde_json::from_str(new).unwrap()), Some(expected));
+        let groups = r#"{"metadata-update": {"remove": {"resource": {"app-name": "groups", "resource": "/ship/~zod/g"}}}}"#;
+        assert_eq!(Chatbot::removed_chat(&serde_json::from_str(groups).unwrap()), None);
+    }
+
+    #[test]
+    fn test_group_left() {
+        let kicked = r#"{"groupUpdate": {"removeMembers": {"resource": {"ship": "~zod", "name": "g"}, "ships": ["~bus", "~nec"]}}}"#;
+        let kicked: serde_json::Value = serde_json::from_str(kicked).unwrap();
+        assert_eq!(Chatbot::group_left(&kicked, "nec"), Some("~zod/g".to_string()));
+        assert_eq!(Chatbot::group_left(&kicked, "wes"), None);
+        let deleted = r#"{"groupUpdate": {"removeGroup": {"resource": {"ship": "zod", "name": "g"}}}}"#;
+        assert_eq!(Chatbot::group_left(&serde_json::from_str(deleted).unwrap(), "wes"), Some("~zod/g".to_string()));
+    }
+
+    #[test]
+    fn test_collect_events_keeps_every_message() {
+        let mut queues: std::collections::HashMap<&str, VecDeque<String>> = std::collections::HashMap::new();
+        queues.insert("graph-store", vec!["m1".to_string(), "m2".to_string(), "m3".to_string()].into());
+        queues.insert("metadata-store", vec!["chat".to_string()].into());
+        queues.insert("btc-wallet", vec!["w1".to_string(), "w2".to_string(), "w3".to_string(), "w4".to_string()].into());
+
+        let events = Chatbot::collect_events(|app, _| queues.get_mut(app).and_then(|queue| queue.pop_front()));
+        assert_eq!(events.iter().filter(|e| matches!(e, InboundEvent::Graph(_))).count(), 3);
+        assert_eq!(events.iter().filter(|e| matches!(e, InboundEvent::Wallet(_))).count(), 4);
+        assert!(events.contains(&InboundEvent::Metadata("chat".to_string())));
+        // Wallet updates keep the loop going after the other queues run dry
+        assert_eq!(events.back(), Some(&InboundEvent::Wallet("w4".to_string())));
+        assert!(queues.values().all(VecDeque::is_empty));
+    }
+
 }
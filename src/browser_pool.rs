@@ -0,0 +1,304 @@
+use headless_chrome::{
+    browser::{default_executable, tab::Tab},
+    protocol::target::methods::CreateTarget,
+    Browser, LaunchOptionsBuilder,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a browser may go without sending any events before
+/// headless_chrome considers it dead. The pool's own health checks take care
+/// of crashed browsers, so this is only a last resort.
+const BROWSER_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Settings for the pool of headless Chrome instances used to render charts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BrowserPoolConfig {
+    /// Most Chrome instances running at once.
+    pub size: usize,
+    /// How often idle instances are checked and replaced if they crashed.
+    pub health_check_interval: Duration,
+    /// How long a request waits for a free instance before giving up.
+    pub checkout_timeout: Duration,
+    /// Whether Chrome runs with its sandbox. Disabling it is needed when
+    /// running as root, e.g. in some containers.
+    pub sandbox: bool,
+    /// File listing the Chrome processes started by the bot, so processes
+    /// left behind by a crash can be cleaned up on the next start.
+    pub pid_file: PathBuf,
+}
+
+/// A reusable tab and what it last loaded.
+pub struct PooledTab {
+    pub tab: Arc<Tab>,
+    /// Identifies the page last loaded in full, so callers can tell whether
+    /// it can be updated in place. `None` if the tab's state is unknown.
+    pub loaded_page: Option<String>,
+}
+
+/// A running Chrome instance with its reusable tabs, one per viewport size.
+pub struct PooledBrowser {
+    browser: Browser,
+    tabs: HashMap<(u16, u16), PooledTab>,
+}
+
+impl PooledBrowser {
+    fn launch(config: &BrowserPoolConfig) -> Result<Self, failure::Error> {
+        let options = LaunchOptionsBuilder::default()
+            .path(Some(default_executable().map_err(failure::err_msg)?))
+            .sandbox(config.sandbox)
+            .idle_browser_timeout(BROWSER_IDLE_TIMEOUT)
+            .build()
+            .map_err(failure::err_msg)?;
+        Ok(PooledBrowser {
+            browser: Browser::new(options)?,
+            tabs: HashMap::new(),
+        })
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        self.browser.get_process_id()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.browser.get_version().is_ok()
+    }
+
+    /// A tab with the given viewport size, reusing the one from an earlier
+    /// request of the same size if there is one.
+    pub fn tab(&mut self, width: u16, height: u16) -> Result<&mut PooledTab, failure::Error> {
+        if !self.tabs.contains_key(&(width, height)) {
+            let tab = self.browser.new_tab_with_options(CreateTarget {
+                url: "about:blank",
+                width: Some(width.into()),
+                height: Some(height.into()),
+                browser_context_id: None,
+                enable_begin_frame_control: None,
+            })?;
+            let tab = PooledTab {
+                tab,
+                loaded_page: None,
+            };
+            self.tabs.insert((width, height), tab);
+        }
+        Ok(self.tabs.get_mut(&(width, height)).unwrap())
+    }
+}
+
+struct PoolState {
+    idle: Vec<PooledBrowser>,
+    /// Instances running, both idle and checked out.
+    running: usize,
+    /// Process ids of the running instances.
+    pids: HashSet<u32>,
+}
+
+/// A fixed-size pool of long-lived headless Chrome instances.
+///
+/// Instances are launched on demand up to `size` and handed out with
+/// `checkout`. An instance that fails a health check, on checkout, on return
+/// or during the periodic checks, is dropped (which kills its process) and
+/// replaced by a fresh one the next time it is needed.
+pub struct BrowserPool {
+    config: BrowserPoolConfig,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+/// A browser checked out of the pool. It goes back to the pool when dropped.
+pub struct BrowserLease<'a> {
+    pool: &'a BrowserPool,
+    browser: Option<PooledBrowser>,
+}
+
+impl Deref for BrowserLease<'_> {
+    type Target = PooledBrowser;
+
+    fn deref(&self) -> &PooledBrowser {
+        self.browser.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for BrowserLease<'_> {
+    fn deref_mut(&mut self) -> &mut PooledBrowser {
+        self.browser.as_mut().unwrap()
+    }
+}
+
+impl Drop for BrowserLease<'_> {
+    fn drop(&mut self) {
+        if let Some(browser) = self.browser.take() {
+            self.pool.give_back(browser);
+        }
+    }
+}
+
+/// Whether `pid` is a running Chrome or Chromium process.
+fn is_chrome_process(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| cmdline.to_lowercase().contains("chrom"))
+        .unwrap_or(false)
+}
+
+impl BrowserPool {
+    /// Create an empty pool, first killing any Chrome processes a previous
+    /// run of the bot left behind.
+    pub fn new(config: BrowserPoolConfig) -> Self {
+        let pool = BrowserPool {
+            config,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                running: 0,
+                pids: HashSet::new(),
+            }),
+            returned: Condvar::new(),
+        };
+        pool.clean_up_orphans();
+        pool
+    }
+
+    fn clean_up_orphans(&self) {
+        let pids = fs::read_to_string(&self.config.pid_file).unwrap_or_default();
+        for pid in pids.lines().filter_map(|line| line.trim().parse::<u32>().ok()) {
+            if is_chrome_process(pid) {
+                println!("Killing orphaned Chrome process {}.", pid);
+                if let Err(e) = Command::new("kill").arg(pid.to_string()).status() {
+                    println!("Failed to kill Chrome process {}: {}", pid, e);
+                }
+            }
+        }
+        self.write_pid_file(&HashSet::new());
+    }
+
+    fn write_pid_file(&self, pids: &HashSet<u32>) {
+        let contents: String = pids.iter().map(|pid| format!("{}\n", pid)).collect();
+        if let Err(e) = fs::write(&self.config.pid_file, contents) {
+            println!("Failed to write {}: {}", self.config.pid_file.display(), e);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Drop a browser, killing its process, and free its slot.
+    fn retire(&self, state: &mut PoolState, browser: PooledBrowser) {
+        if let Some(pid) = browser.process_id() {
+            state.pids.remove(&pid);
+            self.write_pid_file(&state.pids);
+        }
+        state.running -= 1;
+        drop(browser);
+        self.returned.notify_one();
+    }
+
+    /// Take a healthy browser from the pool, launching one if the pool is
+    /// not full yet, or waiting up to `checkout_timeout` for one to be returned.
+    pub fn checkout(&self) -> Result<BrowserLease<'_>, failure::Error> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(browser) = state.idle.pop() {
+                if browser.is_healthy() {
+                    return Ok(BrowserLease {
+                        pool: self,
+                        browser: Some(browser),
+                    });
+                }
+                println!("Replacing unresponsive Chrome instance.");
+                self.retire(&mut state, browser);
+                continue;
+            }
+
+            if state.running < self.config.size {
+                state.running += 1;
+                drop(state);
+                let launched = PooledBrowser::launch(&self.config);
+                let mut state = self.lock();
+                return match launched {
+                    Ok(browser) => {
+                        if let Some(pid) = browser.process_id() {
+                            state.pids.insert(pid);
+                            self.write_pid_file(&state.pids);
+                        }
+                        println!("Launched Chrome instance {}/{}.", state.running, self.config.size);
+                        Ok(BrowserLease {
+                            pool: self,
+                            browser: Some(browser),
+                        })
+                    }
+                    Err(e) => {
+                        state.running -= 1;
+                        self.returned.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(failure::err_msg("Timed out waiting for a free browser"));
+            }
+            state = self
+                .returned
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    fn give_back(&self, browser: PooledBrowser) {
+        let healthy = browser.is_healthy();
+        let mut state = self.lock();
+        if healthy {
+            state.idle.push(browser);
+            self.returned.notify_one();
+        } else {
+            println!("Chrome instance crashed, it will be restarted on the next request.");
+            self.retire(&mut state, browser);
+        }
+    }
+
+    /// Check every idle instance and drop the ones that stopped responding.
+    pub fn health_check(&self) {
+        let idle: Vec<PooledBrowser> = self.lock().idle.drain(..).collect();
+        for browser in idle {
+            self.give_back(browser);
+        }
+    }
+
+    /// Check out every instance at once, launching them as needed, and run
+    /// `prepare` on each. Used to have all of them ready before the first request.
+    pub fn warm_up<F>(&self, prepare: F)
+    where
+        F: Fn(&mut PooledBrowser) -> Result<(), failure::Error>,
+    {
+        let mut leases = vec![];
+        for _ in 0..self.config.size {
+            match self.checkout() {
+                Ok(lease) => leases.push(lease),
+                Err(e) => println!("Failed to start Chrome instance: {}", e),
+            }
+        }
+        for lease in leases.iter_mut() {
+            if let Err(e) = prepare(lease) {
+                println!("Failed to warm up Chrome instance: {}", e);
+            }
+        }
+    }
+
+    /// Run `health_check` every `health_check_interval` on a background thread.
+    pub fn spawn_health_checks(self: &Arc<Self>) {
+        let pool = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(pool.config.health_check_interval);
+            pool.health_check();
+        });
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::str::FromStr;
+use url::Url;
+
+use crate::indicators::{studies_param, Study};
+use crate::interval::Interval;
+use crate::range::ChartRange;
+use crate::style::ChartStyle;
+
+/// Page that renders a single TradingView chart.
+const WIDGET_URL: &str = "https://www.tradingview.com/widgetembed/";
+
+const MAX_EXCHANGE_LEN: usize = 20;
+const MAX_TICKER_LEN: usize = 30;
+
+/// A TradingView symbol, written `TICKER` or `EXCHANGE:TICKER`.
+///
+/// Exchanges may contain letters, digits and `_`. Tickers may also contain
+/// `.`, `!` and `-` (e.g. `BRK.B`, `ES1!`). Both are stored in uppercase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub exchange: Option<String>,
+    pub ticker: String,
+}
+
+/// A symbol that does not follow the symbol grammar.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SymbolError(String);
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+fn validate_part(
+    part: &str,
+    what: &str,
+    max_len: usize,
+    allowed: fn(char) -> bool,
+) -> Result<String, SymbolError> {
+    if part.is_empty() || part.len() > max_len {
+        return Err(SymbolError(format!(
+            "The {} must be between 1 and {} characters long.",
+            what, max_len
+        )));
+    }
+    if let Some(c) = part.chars().find(|c| !allowed(*c)) {
+        return Err(SymbolError(format!(
+            "The {} contains an invalid character `{}`.",
+            what, c
+        )));
+    }
+    Ok(part.to_uppercase())
+}
+
+/// Validate an exchange name such as `binance`, returning it in uppercase.
+pub fn parse_exchange(input: &str) -> Result<String, SymbolError> {
+    validate_part(input, "exchange", MAX_EXCHANGE_LEN, |c| {
+        c.is_ascii_alphanumeric() || c == '_'
+    })
+}
+
+impl FromStr for Symbol {
+    type Err = SymbolError;
+
+    fn from_str(input: &str) -> Result<Symbol, SymbolError> {
+        let (exchange, ticker) = match input.split_once(':') {
+            Some((exchange, ticker)) => (Some(exchange), ticker),
+            None => (None, input),
+        };
+        let exchange = match exchange {
+            Some(exchange) => Some(parse_exchange(exchange)?),
+            None => None,
+        };
+        let ticker = validate_part(ticker, "ticker", MAX_TICKER_LEN, |c| {
+            c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '!' | '-')
+        })?;
+        Ok(Symbol { exchange, ticker })
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.exchange {
+            Some(exchange) => write!(f, "{}:{}", exchange, self.ticker),
+            None => write!(f, "{}", self.ticker),
+        }
+    }
+}
+
+/// Everything needed to render one chart. Every field is validated when it
+/// is parsed, and `to_url` encodes each of them, so user input can never add
+/// parameters of its own to the page we screenshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChartRequest {
+    pub symbol: Symbol,
+    pub interval: Interval,
+    pub range: Option<ChartRange>,
+    pub studies: Vec<Study>,
+    pub style: ChartStyle,
+}
+
+impl ChartRequest {
+    pub fn new(symbol: Symbol, interval: Interval) -> Self {
+        ChartRequest {
+            symbol,
+            interval,
+            range: None,
+            studies: vec![],
+            style: ChartStyle::default(),
+        }
+    }
+
+    /// Every widget parameter except the symbol and interval.
+    fn layout_params(&self) -> Vec<(&'static str, String)> {
+        let mut params: Vec<(&str, String)> = vec![
+            ("hidetoptoolbar", "1".to_string()),
+            ("symboledit", "1".to_string()),
+            ("saveimage", "1".to_string()),
+            ("withdateranges", "1".to_string()),
+        ];
+        params.extend(self.style.url_params());
+        if let Some(range) = &self.range {
+            params.extend(range.url_params());
+        }
+        if !self.studies.is_empty() {
+            params.push(("studies", studies_param(&self.studies)));
+        }
+        params
+    }
+
+    /// The TradingView widget URL for this chart.
+    pub fn to_url(&self) -> Url {
+        let mut params: Vec<(&str, String)> = vec![
+            ("symbol", self.symbol.to_string()),
+            ("interval", self.interval.to_tradingview()),
+        ];
+        params.extend(self.layout_params());
+        Url::parse_with_params(WIDGET_URL, &params).expect("the widget URL is valid")
+    }
+
+    /// Identifies the chart's layout: everything but the symbol and interval.
+    /// A tab already showing a chart with the same layout can switch to this
+    /// one in place instead of loading the page again. Charts with a date
+    /// range have no layout key, since switching symbols moves the visible range.
+    pub fn layout_key(&self) -> Option<String> {
+        if self.range.is_some() {
+            return None;
+        }
+        let url = Url::parse_with_params(WIDGET_URL, &self.layout_params()).expect("the widget URL is valid");
+        Some(url.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_pairs(url: &Url) -> Vec<(String, String)> {
+        url.query_pairs()
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_symbol_grammar() {
+        assert_eq!(
+            "binance:btcusdt".parse(),
+            Ok(Symbol {
+                exchange: Some("BINANCE".to_string()),
+                ticker: "BTCUSDT".to_string()
+            })
+        );
+        assert_eq!(
+            "es1!".parse(),
+            Ok(Symbol {
+                exchange: None,
+                ticker: "ES1!".to_string()
+            })
+        );
+        assert!("BRK.B".parse::<Symbol>().is_ok());
+    }
+
+    #[test]
+    fn test_symbol_rejects_injection() {
+        assert!("btcusd&theme=light".parse::<Symbol>().is_err());
+        assert!("btc usd".parse::<Symbol>().is_err());
+        assert!("a:b:c".parse::<Symbol>().is_err());
+        assert!(":btcusd".parse::<Symbol>().is_err());
+        assert!("binance:".parse::<Symbol>().is_err());
+        assert!("x".repeat(31).parse::<Symbol>().is_err());
+        assert!("%26theme".parse::<Symbol>().is_err());
+    }
+
+    #[test]
+    fn test_to_url_encodes_every_field() {
+        let mut request = ChartRequest::new("coinbase:btcusd".parse().unwrap(), Interval::Hours(4));
+        request.studies.push(Study {
+            study_id: "RSI@tv-basicstudies".to_string(),
+            inputs: vec![],
+        });
+        let url = request.to_url();
+        assert_eq!(url.host_str(), Some("www.tradingview.com"));
+        assert!(url.as_str().contains("symbol=COINBASE%3ABTCUSD"));
+        let pairs = query_pairs(&url);
+        assert!(pairs.contains(&("interval".to_string(), "240".to_string())));
+        assert!(pairs.contains(&(
+            "studies".to_string(),
+            r#"[{"id":"RSI@tv-basicstudies"}]"#.to_string()
+        )));
+        // The layout ignores symbol and interval
+        let mut other = ChartRequest::new("btcusd".parse().unwrap(), Interval::Days(1));
+        other.studies = request.studies.clone();
+        assert_eq!(other.layout_key(), request.layout_key());
+        other.range = Some(ChartRange::All);
+        assert_eq!(other.layout_key(), None);
+        // Each parameter appears exactly once
+        let mut keys: Vec<&String> = pairs.iter().map(|(key, _)| key).collect();
+        let count = keys.len();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), count);
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+
+use super::{require_admin, require_dm, Command, CommandContext, CommandError, CommandSpec, Message};
+use crate::invites::Invites;
+
+/// `approve [id]`: list the group invites held for approval, or accept one.
+/// Only bot admins may use it, in a direct message to the bot.
+pub struct ApproveCommand {
+    invites: Arc<Invites>,
+    admins: Vec<String>,
+}
+
+impl ApproveCommand {
+    pub fn new(invites: Arc<Invites>, admins: Vec<String>) -> Self {
+        ApproveCommand { invites, admins }
+    }
+}
+
+impl Command for ApproveCommand {
+    type Args = Option<u64>;
+
+    fn spec(&self) -> CommandSpec {
+        CommandSpec {
+            name: "approve",
+            aliases: &[],
+            usage: "approve [id]",
+            description: "Admins only, in a direct message. List group invites waiting for approval, or accept the invite `id`.",
+        }
+    }
+
+    fn parse(&self, context: &CommandContext, args: &[String]) -> Result<Option<u64>, CommandError> {
+        require_admin(context, &self.admins)?;
+        require_dm(context)?;
+        match args {
+            [] => Ok(None),
+            [id] => id
+                .trim_start_matches('#')
+                .parse()
+                .map(Some)
+                .map_err(|_| CommandError::InvalidArguments(format!("`{}` is not an invite id.", id))),
+            _ => Err(CommandError::InvalidArguments("Approve one invite at a time.".to_string())),
+        }
+    }
+
+    fn execute(&self, _context: &CommandContext, id: Option<u64>) -> Result<Message, CommandError> {
+        if let Some(id) = id {
+            return match self.invites.approve(id) {
+                Ok(Some(invite)) => Ok(Message::new().add_text(&format!("Joining {}.", invite.group()))),
+                Ok(None) => Err(CommandError::Failed(format!("There is no invite #{} waiting for approval.", id))),
+                Err(e) => Err(CommandError::Failed(format!("Failed to approve the invite: {}", e))),
+            };
+        }
+
+        let held = self.invites.held();
+        if held.is_empty() {
+            return Ok(Message::new().add_text("No invites are waiting for approval."));
+        }
+        let mut text = format!("{} invites waiting for approval:", held.len());
+        for (id, invite) in held {
+            let received = NaiveDateTime::from_timestamp(invite.received_at as i64, 0);
+            text.push_str(&format!(
+                "\n`#{}` {} from {}, {} UTC",
+                id,
+                invite.group(),
+                invite.inviter,
+                received.format("%Y-%m-%d %H:%M")
+            ));
+        }
+        Ok(Message::new().add_text(&text))
+    }
+}
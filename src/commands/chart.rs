@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::{Command, CommandContext, CommandError, CommandSpec, Message};
+use crate::browser_pool::BrowserPool;
+use crate::chart_request::{ChartRequest, Symbol};
+use crate::config::BotConfig;
+use crate::indicators::{parse_indicator, IndicatorTable};
+use crate::interval::Interval;
+use crate::range::ChartRange;
+use crate::screenshot::screenshot_tab;
+use crate::storage::{chart_key, ImageStore, PrivateLinks};
+use crate::symbol_index::SymbolIndex;
+use crate::symbols::SymbolTable;
+
+fn invalid<E: ToString>(err: E) -> CommandError {
+    CommandError::InvalidArguments(err.to_string())
+}
+
+/// `reply` to a trading pair that cannot be charted, followed by close
+/// matches from the index, if there is one.
+fn unknown_pair(index: Option<&SymbolIndex>, symbol: &Symbol, mut reply: String) -> CommandError {
+    if let Some(index) = index {
+        let suggestions: Vec<String> = index
+            .suggest(symbol, 3)
+            .iter()
+            .map(|entry| format!("`{}`", entry.symbol))
+            .collect();
+        if !suggestions.is_empty() {
+            reply.push_str(&format!(" Did you mean {}?", suggestions.join(", ")));
+        }
+        reply.push_str(" Use `s <query>` to search.");
+    }
+    CommandError::Failed(reply)
+}
+
+/// `c <trading_pair> <timeframe>`: screenshot a TradingView chart and post a link to it.
+pub struct ChartCommand {
+    indicators: IndicatorTable,
+    max_indicators: usize,
+    symbols: SymbolTable,
+    index: Option<Arc<SymbolIndex>>,
+    browsers: Arc<BrowserPool>,
+    images: Arc<dyn ImageStore>,
+    private_links: PrivateLinks,
+}
+
+impl ChartCommand {
+    pub fn new(
+        config: &BotConfig,
+        index: Option<Arc<SymbolIndex>>,
+        browsers: Arc<BrowserPool>,
+        images: Arc<dyn ImageStore>,
+    ) -> Self {
+        ChartCommand {
+            indicators: config.indicators.clone(),
+            max_indicators: config.max_indicators,
+            symbols: config.symbols.clone(),
+            index,
+            browsers,
+            images,
+            private_links: config.private_links.clone(),
+        }
+    }
+
+    fn indicator_names(&self) -> String {
+        let mut names: Vec<&str> = self.indicators.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names.join(", ")
+    }
+}
+
+impl Command for ChartCommand {
+    type Args = ChartRequest;
+
+    fn spec(&self) -> CommandSpec {
+        CommandSpec {
+            name: "c",
+            aliases: &["chart"],
+            usage: "c [exchange:]<trading_pair> <timeframe> [last <period> | from <date> [to <date>] | ytd | all] [indicators] [style]",
+            description: "Get the TradingView chart for any trading pair and timeframe. Indicators: e.g. `rsi ema:200`. Style: `--light`, `--log`, `line`, `area`, `heikin`, `renko`, `wide`, `square`. Examples: `c ethusd 4h rsi ema:200`, `c btcusd 1D last 3y --light wide`",
+        }
+    }
+
+    fn parse(&self, context: &CommandContext, args: &[String]) -> Result<ChartRequest, CommandError> {
+        let (symbol, rest) = match args {
+            [symbol, rest @ ..] if !rest.is_empty() => (symbol, rest),
+            _ => {
+                return Err(CommandError::InvalidArguments(
+                    "Please provide a trading pair and a timeframe.".to_string(),
+                ))
+            }
+        };
+        let symbol = self.symbols.resolve(symbol, &context.chat).map_err(invalid)?;
+        if let Some(index) = &self.index {
+            if !index.contains(&symbol) {
+                return Err(unknown_pair(Some(index), &symbol, format!("Unknown trading pair `{}`.", symbol)));
+            }
+        }
+        let (interval, used) = Interval::parse_words(rest).map_err(invalid)?;
+        let mut request = ChartRequest::new(symbol, interval);
+
+        let today = chrono::offset::Utc::today().naive_utc();
+        let mut options = &rest[used..];
+        while let Some(word) = options.first() {
+            if let Some((parsed_range, used)) = ChartRange::parse_words(options, today).map_err(invalid)? {
+                if request.range.is_some() {
+                    return Err(invalid("Only one date range can be given."));
+                }
+                request.range = Some(parsed_range);
+                options = &options[used..];
+                continue;
+            }
+            if request.style.apply(word) {
+                options = &options[1..];
+                continue;
+            }
+            if let Some(study) = parse_indicator(word, &self.indicators).map_err(invalid)? {
+                request.studies.push(study);
+                options = &options[1..];
+                continue;
+            }
+            return Err(invalid(format!(
+                "Unknown option `{}`. Available indicators: {}",
+                word,
+                self.indicator_names()
+            )));
+        }
+        if request.studies.len() > self.max_indicators {
+            return Err(invalid(format!(
+                "At most {} indicators can be added to a chart.",
+                self.max_indicators
+            )));
+        }
+
+        Ok(request)
+    }
+
+    fn execute(&self, context: &CommandContext, request: ChartRequest) -> Result<Message, CommandError> {
+        let now = Instant::now(); // initiate timer
+        println!("Getting screenshot from {}", request.to_url());
+        let shot = screenshot_tab(&self.browsers, &request).map_err(|err| {
+            println!("Error rendering chart: {}", err);
+            let reply = format!("Trading pair `{}` not available.", request.symbol);
+            unknown_pair(self.index.as_deref(), &request.symbol, reply)
+        })?;
+        println!("Got TradingView screenshot, uploading it.");
+
+        let access = self.private_links.access(&context.chat, context.group.as_deref());
+        let key = chart_key(
+            &request.symbol.to_string(),
+            request.interval,
+            chrono::Utc::now(),
+            &shot,
+            access,
+        );
+        let file_location = self.images.put_png(&key, &shot, access).map_err(|err| {
+            println!("Error uploading the chart: {}", err);
+            CommandError::Failed("Could not upload the chart, please try again later.".to_string())
+        })?;
+        println!("Uploaded the chart. Sending URL to chat. Took {} seconds to process command.", now.elapsed().as_secs());
+
+        Ok(Message::new().add_url(file_location.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_pair_suggestions() {
+        let index = SymbolIndex::from_csv_str("BITSTAMP,BTCUSD,Bitcoin / U.S. dollar").unwrap();
+        let symbol: Symbol = "BITSTAMP:BTCUDS".parse().unwrap();
+        let reply = |index| unknown_pair(index, &symbol, "Trading pair `BITSTAMP:BTCUDS` not available.".to_string()).to_string();
+        assert_eq!(
+            reply(Some(&index)),
+            "Trading pair `BITSTAMP:BTCUDS` not available. Did you mean `BITSTAMP:BTCUSD`? Use `s <query>` to search."
+        );
+        assert_eq!(reply(None), "Trading pair `BITSTAMP:BTCUDS` not available.");
+    }
+}
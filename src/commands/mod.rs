@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt;
+
+pub use urbit_http_api::Message;
+
+use crate::bot::ShipChat;
+
+pub mod approve;
+pub mod chart;
+pub mod outbox;
+pub mod search;
+
+/// Static description of a command, used both for dispatch and to build
+/// the help menu.
+#[derive(Clone, Copy)]
+pub struct CommandSpec {
+    /// The word that invokes the command, e.g. `c`.
+    pub name: &'static str,
+    /// Other words that invoke the same command.
+    pub aliases: &'static [&'static str],
+    /// One line showing the expected arguments.
+    pub usage: &'static str,
+    /// Short explanation shown in the help menu.
+    pub description: &'static str,
+}
+
+/// Where a command was sent from.
+#[derive(Clone, Debug)]
+pub struct CommandContext {
+    /// The chat the command was posted in, where the reply goes.
+    pub chat: ShipChat,
+    /// The ship that sent the command, e.g. `~zod`.
+    pub author: String,
+    /// The group the chat belongs to, as `~ship/group-name`, if it is known.
+    pub group: Option<String>,
+}
+
+/// Errors a command can surface back to the chat.
+#[derive(Debug)]
+pub enum CommandError {
+    /// The arguments could not be parsed. The usage line is appended to the reply.
+    InvalidArguments(String),
+    /// The arguments were fine but the command could not be completed.
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::InvalidArguments(reason) => write!(f, "{}", reason),
+            CommandError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Reject the command unless it was sent by one of `admins`.
+pub fn require_admin(context: &CommandContext, admins: &[String]) -> Result<(), CommandError> {
+    if admins.contains(&context.author) {
+        Ok(())
+    } else {
+        Err(CommandError::Failed("Only bot admins can use this command.".to_string()))
+    }
+}
+
+/// Reject the command unless it was sent in a direct message to the bot.
+pub fn require_dm(context: &CommandContext) -> Result<(), CommandError> {
+    if context.chat.is_dm() {
+        Ok(())
+    } else {
+        Err(CommandError::Failed("Send this command in a direct message to the bot.".to_string()))
+    }
+}
+
+/// A chat command. Each command owns its argument parser, its help text and
+/// its execution, so new commands can be added by registering them with a
+/// `CommandRegistry` instead of editing the message handler.
+pub trait Command: Send + Sync {
+    /// The parsed form of the command's arguments.
+    type Args;
+
+    fn spec(&self) -> CommandSpec;
+
+    /// Parse the words following the command name. This must not do any
+    /// expensive work so bad input is rejected before anything is rendered.
+    fn parse(&self, context: &CommandContext, args: &[String]) -> Result<Self::Args, CommandError>;
+
+    /// Run the command with already validated arguments.
+    fn execute(&self, context: &CommandContext, args: Self::Args) -> Result<Message, CommandError>;
+}
+
+/// Object safe wrapper around `Command` so commands with different `Args`
+/// types can live in the same registry.
+trait Handler: Send + Sync {
+    fn spec(&self) -> CommandSpec;
+    fn handle(&self, context: &CommandContext, args: &[String]) -> Result<Message, CommandError>;
+}
+
+impl<C: Command> Handler for C {
+    fn spec(&self) -> CommandSpec {
+        Command::spec(self)
+    }
+
+    fn handle(&self, context: &CommandContext, args: &[String]) -> Result<Message, CommandError> {
+        let parsed = self.parse(context, args)?;
+        self.execute(context, parsed)
+    }
+}
+
+/// Name of the built-in command that lists every registered command.
+const HELP_COMMAND: &str = "help";
+
+/// A set of commands keyed by their name and aliases.
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<Box<dyn Handler>>,
+    lookup: HashMap<String, usize>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a command to the registry. Panics if its name or one of its aliases
+    /// is already taken, since that is a programming error.
+    pub fn register<C: Command + 'static>(mut self, command: C) -> Self {
+        let spec = Command::spec(&command);
+        let index = self.commands.len();
+        for key in std::iter::once(&spec.name).chain(spec.aliases.iter()) {
+            let key = key.to_lowercase();
+            assert!(
+                key != HELP_COMMAND && !self.lookup.contains_key(&key),
+                "command name `{}` is registered twice",
+                key
+            );
+            self.lookup.insert(key, index);
+        }
+        self.commands.push(Box::new(command));
+        self
+    }
+
+    /// Find the command invoked by `words` and run it. Returns `None` if the
+    /// first word is not a known command, so ordinary chatter is ignored.
+    pub fn dispatch(&self, context: &CommandContext, words: &[String]) -> Option<Message> {
+        let (name, args) = words.split_first()?;
+        let name = name.to_lowercase();
+        if name == HELP_COMMAND {
+            return Some(Message::new().add_text(&self.help_text()));
+        }
+        let handler = &self.commands[*self.lookup.get(&name)?];
+
+        let reply = match handler.handle(context, args) {
+            Ok(message) => message,
+            Err(CommandError::InvalidArguments(reason)) => Message::new().add_text(&format!(
+                "{}\nUsage: `{}`",
+                reason,
+                handler.spec().usage
+            )),
+            Err(CommandError::Failed(reason)) => Message::new().add_text(&reason),
+        };
+        Some(reply)
+    }
+
+    /// The help menu, generated from the registered commands.
+    pub fn help_text(&self) -> String {
+        let mut text = String::from("Available commands:");
+        for handler in &self.commands {
+            let spec = handler.spec();
+            text.push_str(&format!("\n`{}` – {}", spec.usage, spec.description));
+            if !spec.aliases.is_empty() {
+                text.push_str(&format!(" (aliases: {})", spec.aliases.join(", ")));
+            }
+        }
+        text.push_str(&format!("\n`{}` – Show this menu.", HELP_COMMAND));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat;
+
+    struct Echo;
+
+    impl Command for Echo {
+        type Args = String;
+
+        fn spec(&self) -> CommandSpec {
+            CommandSpec {
+                name: "echo",
+                aliases: &["e"],
+                usage: "echo <word>",
+                description: "Repeat a word.",
+            }
+        }
+
+        fn parse(&self, _context: &CommandContext, args: &[String]) -> Result<String, CommandError> {
+            match args {
+                [word] => Ok(word.clone()),
+                _ => Err(CommandError::InvalidArguments("Expected one word.".to_string())),
+            }
+        }
+
+        fn execute(&self, _context: &CommandContext, word: String) -> Result<Message, CommandError> {
+            Ok(Message::new().add_text(&word))
+        }
+    }
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn context() -> CommandContext {
+        CommandContext {
+            chat: chat("chat-1"),
+            author: "~bus".to_string(),
+            group: None,
+        }
+    }
+
+    #[test]
+    fn test_dispatch_by_name_and_alias() {
+        let registry = CommandRegistry::new().register(Echo);
+        let reply = registry.dispatch(&context(), &words("E hello")).unwrap();
+        assert_eq!(reply.to_formatted_string().trim(), "hello");
+        assert!(registry.dispatch(&context(), &words("hello there")).is_none());
+        assert!(registry.dispatch(&context(), &[]).is_none());
+    }
+
+    #[test]
+    fn test_invalid_arguments_include_usage() {
+        let registry = CommandRegistry::new().register(Echo);
+        let reply = registry.dispatch(&context(), &words("echo")).unwrap();
+        assert!(reply.to_formatted_string().contains("Usage: `echo <word>`"));
+    }
+
+    #[test]
+    fn test_help_lists_commands() {
+        let registry = CommandRegistry::new().register(Echo);
+        let help = registry.dispatch(&context(), &words("help")).unwrap().to_formatted_string();
+        assert!(help.contains("`echo <word>` – Repeat a word. (aliases: e)"));
+    }
+
+    #[test]
+    fn test_require_admin() {
+        assert!(require_admin(&context(), &["~bus".to_string()]).is_ok());
+        assert!(require_admin(&context(), &["~zod".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_require_dm() {
+        assert!(require_dm(&context()).is_err());
+        let mut dm = context();
+        dm.chat.chat_name = "dm-inbox".to_string();
+        assert!(require_dm(&dm).is_ok());
+        dm.chat.chat_name = "dm--bus".to_string();
+        assert!(require_dm(&dm).is_ok());
+    }
+}
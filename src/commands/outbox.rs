@@ -0,0 +1,73 @@
+use chrono::NaiveDateTime;
+use std::sync::Arc;
+
+use super::{require_admin, Command, CommandContext, CommandError, CommandSpec, Message};
+use crate::outbox::Outbox;
+
+/// Most failed replies listed at once.
+const MAX_LISTED: usize = 10;
+
+pub enum OutboxAction {
+    Show,
+    Retry,
+}
+
+/// `outbox [retry]`: show replies the bot could not send, or queue them again.
+/// Only bot admins may use it.
+pub struct OutboxCommand {
+    outbox: Arc<Outbox>,
+    admins: Vec<String>,
+}
+
+impl OutboxCommand {
+    pub fn new(outbox: Arc<Outbox>, admins: Vec<String>) -> Self {
+        OutboxCommand { outbox, admins }
+    }
+}
+
+impl Command for OutboxCommand {
+    type Args = OutboxAction;
+
+    fn spec(&self) -> CommandSpec {
+        CommandSpec {
+            name: "outbox",
+            aliases: &[],
+            usage: "outbox [retry]",
+            description: "Admins only. List replies that could not be sent, or `retry` them.",
+        }
+    }
+
+    fn parse(&self, context: &CommandContext, args: &[String]) -> Result<OutboxAction, CommandError> {
+        require_admin(context, &self.admins)?;
+        match args {
+            [] => Ok(OutboxAction::Show),
+            [action] if action.eq_ignore_ascii_case("retry") => Ok(OutboxAction::Retry),
+            _ => Err(CommandError::InvalidArguments("Unknown outbox action.".to_string())),
+        }
+    }
+
+    fn execute(&self, _context: &CommandContext, action: OutboxAction) -> Result<Message, CommandError> {
+        if let OutboxAction::Retry = action {
+            let count = self.outbox.retry_failed();
+            return Ok(Message::new().add_text(&format!("Queued {} failed replies again.", count)));
+        }
+
+        let failures = self.outbox.failures();
+        let mut text = format!(
+            "{} replies waiting to be sent, {} failed.",
+            self.outbox.pending_count(),
+            failures.len()
+        );
+        for entry in failures.iter().rev().take(MAX_LISTED) {
+            let queued = NaiveDateTime::from_timestamp(entry.queued_at as i64, 0);
+            text.push_str(&format!(
+                "\n`{}` queued {} UTC, {} attempts: {}",
+                entry.chat.key(),
+                queued.format("%Y-%m-%d %H:%M"),
+                entry.attempts,
+                entry.last_error.as_deref().unwrap_or("unknown error")
+            ));
+        }
+        Ok(Message::new().add_text(&text))
+    }
+}
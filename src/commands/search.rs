@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use super::{Command, CommandContext, CommandError, CommandSpec, Message};
+use crate::symbol_index::SymbolIndex;
+
+/// Most results listed for one search.
+const MAX_RESULTS: usize = 10;
+
+/// `s <query>`: list tickers from the local symbol index that match a query.
+pub struct SearchCommand {
+    index: Option<Arc<SymbolIndex>>,
+}
+
+impl SearchCommand {
+    pub fn new(index: Option<Arc<SymbolIndex>>) -> Self {
+        SearchCommand { index }
+    }
+}
+
+impl Command for SearchCommand {
+    type Args = String;
+
+    fn spec(&self) -> CommandSpec {
+        CommandSpec {
+            name: "s",
+            aliases: &["search"],
+            usage: "s <query>",
+            description: "Search for trading pairs and the exchanges that list them. Example: `s btc`",
+        }
+    }
+
+    fn parse(&self, _context: &CommandContext, args: &[String]) -> Result<String, CommandError> {
+        if args.is_empty() {
+            return Err(CommandError::InvalidArguments(
+                "Please provide something to search for.".to_string(),
+            ));
+        }
+        Ok(args.join(" "))
+    }
+
+    fn execute(&self, _context: &CommandContext, query: String) -> Result<Message, CommandError> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or_else(|| CommandError::Failed("Symbol search is not set up on this bot.".to_string()))?;
+        let results = index.search(&query, MAX_RESULTS);
+        if results.is_empty() {
+            return Err(CommandError::Failed(format!("No trading pairs match `{}`.", query)));
+        }
+        let mut text = format!("Trading pairs matching `{}`:", query);
+        for entry in results {
+            text.push_str(&format!("\n`{}` – {}", entry.symbol, entry.description));
+        }
+        Ok(Message::new().add_text(&text))
+    }
+}
@@ -0,0 +1,628 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::browser_pool::BrowserPoolConfig;
+use crate::chart_request::{parse_exchange, Symbol};
+use crate::indicators::{IndicatorConfig, IndicatorTable};
+use crate::invites::{InviteAction, InvitePolicy};
+use crate::outbox::OutboxConfig;
+use crate::patp::{self, Rank};
+use crate::retention::RetentionConfig;
+use crate::ship_events::Backoff;
+use crate::storage::{PrivateLinks, StorageConfig, MAX_PRESIGN_EXPIRY};
+use crate::symbols::SymbolTable;
+use crate::workers::WorkerPoolConfig;
+
+/// Where the bot looks for its configuration.
+const BOT_CONFIG_FILE: &str = "bot_config.yaml";
+
+/// Written to `bot_config.yaml` on first run and used as the default.
+static DEFAULT_BOT_CONFIG_YAML: &str = r#"
+# Most indicators a single chart request may add
+max_indicators: 5
+# Indicators users can add to charts, e.g. `c ethusd 4h rsi macd ema:200`.
+# `id` is the TradingView study id, `inputs` are its default inputs and
+# `parameter` is the input set by a value such as the 200 in `ema:200`.
+indicators:
+  rsi:
+    id: "RSI@tv-basicstudies"
+    inputs:
+      length: 14
+    parameter: "length"
+  macd:
+    id: "MACD@tv-basicstudies"
+  bb:
+    id: "BB@tv-basicstudies"
+    inputs:
+      length: 20
+    parameter: "length"
+  ema:
+    id: "MAExp@tv-basicstudies"
+    inputs:
+      length: 9
+    parameter: "length"
+  sma:
+    id: "MASimple@tv-basicstudies"
+    inputs:
+      length: 9
+    parameter: "length"
+  vwap:
+    id: "VWAP@tv-basicstudies"
+  stoch:
+    id: "Stochastic@tv-basicstudies"
+  vol:
+    id: "Volume@tv-basicstudies"
+# How symbols without an `EXCHANGE:` prefix are resolved, e.g. `c btc 4h`.
+symbols:
+  # Exchange used when neither an alias nor the chat picks one. Leave empty
+  # to let TradingView choose.
+  default_exchange: ""
+  # Shorthands for full symbols
+  aliases:
+    btc: "BITSTAMP:BTCUSD"
+    eth: "BITSTAMP:ETHUSD"
+  # Default exchange per chat, keyed by `~ship/chat-name`
+  chat_exchanges: {}
+# CSV or JSON file listing known symbols, used for `s <query>` and to suggest
+# symbols for typos. Leave empty to disable. See `symbols.example.csv`.
+symbol_index: ""
+# Headless Chrome instances used to render charts
+browser:
+  # Most Chrome instances running at once
+  pool_size: 2
+  # Seconds between health checks of idle instances
+  health_check_secs: 60
+  # Seconds a request waits for a free instance
+  checkout_timeout_secs: 30
+  # Set to false when running as root, e.g. in some containers
+  sandbox: true
+  # Chrome processes started by the bot, cleaned up after a crash
+  pid_file: "chrome.pids"
+  # Load a chart in every instance on startup, so plain chart requests only
+  # switch symbols instead of loading TradingView from scratch
+  prewarm: true
+  # Symbol shown while warming up
+  prewarm_symbol: "BITSTAMP:BTCUSD"
+# Where chart images are uploaded
+storage:
+  # s3: Amazon S3, or an S3 compatible service such as MinIO if `endpoint` is
+  # set. The bucket, region and keys are read from `S3_BUCKET`, `S3_REGION`,
+  # `AWS_ID` and `AWS_SECRET` in `.env`.
+  # ship: the S3 bucket and credentials set up in Landscape on the bot's ship,
+  # with `path_style`, `public_url` and `presign_expiry_hours` from `s3` below.
+  # local: a directory on this machine, served by the bot or another web server.
+  backend: s3
+  s3:
+    # e.g. "http://localhost:9000" for MinIO. Leave empty for Amazon S3.
+    endpoint: ""
+    # Address the bucket as `endpoint/bucket` rather than `bucket.endpoint`,
+    # as MinIO expects. Only used with an `endpoint` or the ship backend.
+    path_style: false
+    # URL images are linked with, e.g. a CDN in front of the bucket. Leave
+    # empty to link to the bucket itself.
+    public_url: ""
+    # Hours private links stay valid, at most 168 (7 days)
+    presign_expiry_hours: 24
+  # Charts posted in these chats, written as `~ship/chat-name`, or in any chat
+  # of these groups, written as `~ship/group-name`, are linked with presigned
+  # URLs that stop working after `presign_expiry_hours`, so they cannot be
+  # shared forever. They are uploaded under `charts/private/` with a private
+  # ACL: a bucket policy allowing public reads must leave that prefix out.
+  # Needs the s3 or ship backend: local images are always linked publicly.
+  private_chats: []
+  # Link every chart privately, for buckets that do not allow public reads
+  private_everywhere: false
+  local:
+    dir: "charts"
+    # Address the built-in web server listens on. Leave empty when another
+    # web server publishes `dir`.
+    listen: "0.0.0.0:8080"
+    # URL `dir` is published at, as seen by chat members
+    public_url: "http://localhost:8080"
+# Deleting old chart images, to keep storage costs down
+retention:
+  # Images older than this many days are deleted. 0 keeps them forever.
+  max_age_days: 0
+  # How often old images are looked for
+  interval_hours: 24
+  # Only log the images that would be deleted and the space it would free
+  dry_run: false
+  # With S3, install a lifecycle rule so the bucket deletes old images itself
+  # instead. S3 only lets the whole lifecycle configuration be replaced, so
+  # the bot reads the bucket's rules and writes them back along with its own.
+  # Rules changed elsewhere at the same moment may be lost.
+  lifecycle_rule: false
+# Ships allowed to use admin commands such as `outbox`, e.g. ["~zod"]
+admins: []
+# Posted once in every chat the bot joins. `{bot}` is replaced with the bot's
+# ship, `{chat}` with the chat's name and `{group}` with its group. Leave empty
+# to post nothing.
+welcome: "Hi, I'm {bot}. I post TradingView charts for any trading pair: try `c btcusd 4h` for a 4 hour chart or `c ethusd 1D rsi` to add an indicator. Send `help` to see every command."
+# Which group invites the bot accepts
+invites:
+  # What to do with invites no rule below matches: accept, ignore, or hold
+  # them until an admin accepts them with `approve <id>`
+  default: accept
+  # Invites from these ships, or to these groups (`~ship/group-name`), are accepted
+  allow_ships: []
+  allow_groups: []
+  # Invites from or to groups hosted by these ships are ignored
+  deny_ships: []
+  # Invites from or to groups hosted by ships of these ranks are ignored:
+  # galaxy, star, planet, moon or comet
+  deny_ranks: []
+  # Ignore invites from ships that have not paid into the bot's wallet
+  require_customer: false
+  # Ships that count as customers without a payment, e.g. ["~zod"]
+  customers: []
+# Replies that could not be sent are kept here and retried
+outbox:
+  file: "outbox.json"
+  # Attempts at sending a reply before giving up on it
+  max_attempts: 8
+  # Seconds before the first retry, doubled after every failed attempt
+  retry_delay_secs: 5
+  # Longest wait between two attempts, in seconds
+  max_retry_delay_secs: 600
+# Database with the bot's state, such as the groups and chats it has joined
+# and the messages it has answered
+state_db: "bot_state"
+# How long to wait before reconnecting after the connection to the ship drops.
+# The wait doubles after every failed attempt, up to the maximum.
+reconnect:
+  initial_delay_secs: 1
+  max_delay_secs: 300
+# Threads that run chat commands
+workers:
+  # Commands handled at the same time, across all chats
+  threads: 4
+  # Commands a single chat may have waiting before new ones are turned away
+  queue_per_chat: 5
+"#;
+
+/// Settings for the bot itself, as opposed to the ship it runs on.
+#[derive(Clone, Debug)]
+pub struct BotConfig {
+    pub max_indicators: usize,
+    pub indicators: IndicatorTable,
+    pub symbols: SymbolTable,
+    /// Path of the local symbol index, if there is one.
+    pub symbol_index: Option<String>,
+    pub browser: BrowserPoolConfig,
+    /// Symbol to load in every browser on startup, if warming up is enabled.
+    pub prewarm_symbol: Option<Symbol>,
+    pub storage: StorageConfig,
+    /// Chats whose charts are linked privately.
+    pub private_links: PrivateLinks,
+    pub retention: RetentionConfig,
+    pub workers: WorkerPoolConfig,
+    /// Ships allowed to use admin commands, written as `~ship`.
+    pub admins: Vec<String>,
+    /// Message posted in chats the bot joins, if any.
+    pub welcome: Option<String>,
+    pub invites: InvitePolicy,
+    pub outbox: OutboxConfig,
+    pub reconnect: Backoff,
+    /// Directory of the state database.
+    pub state_db: PathBuf,
+}
+
+/// The config file could not be read or is malformed.
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn yaml_number(yaml: &Yaml) -> Option<f64> {
+    match yaml {
+        Yaml::Integer(n) => Some(*n as f64),
+        Yaml::Real(_) => yaml.as_f64(),
+        _ => None,
+    }
+}
+
+fn parse_indicators(yaml: &Yaml) -> Result<IndicatorTable, ConfigError> {
+    let mut table = IndicatorTable::new();
+    let entries = match yaml {
+        Yaml::BadValue | Yaml::Null => return Ok(table),
+        other => other
+            .as_hash()
+            .ok_or_else(|| ConfigError("`indicators` must be a map".to_string()))?,
+    };
+    for (name, entry) in entries {
+        let name = name
+            .as_str()
+            .ok_or_else(|| ConfigError("indicator names must be strings".to_string()))?
+            .to_lowercase();
+        let study_id = entry["id"]
+            .as_str()
+            .ok_or_else(|| ConfigError(format!("indicator `{}` needs an `id`", name)))?
+            .to_string();
+        let mut inputs = vec![];
+        if let Some(input_entries) = entry["inputs"].as_hash() {
+            for (input, value) in input_entries {
+                let input = input.as_str().map(String::from);
+                match (input, yaml_number(value)) {
+                    (Some(input), Some(value)) => inputs.push((input, value)),
+                    _ => return Err(ConfigError(format!("indicator `{}` has an invalid input", name))),
+                }
+            }
+        }
+        let parameter = entry["parameter"].as_str().map(String::from);
+        table.insert(
+            name,
+            IndicatorConfig {
+                study_id,
+                inputs,
+                parameter,
+            },
+        );
+    }
+    Ok(table)
+}
+
+fn parse_symbols(yaml: &Yaml) -> Result<SymbolTable, ConfigError> {
+    let mut table = SymbolTable::default();
+    let invalid = |what: &str, e| ConfigError(format!("invalid {} in `symbols`: {}", what, e));
+
+    match &yaml["default_exchange"] {
+        Yaml::String(exchange) if !exchange.is_empty() => {
+            table.default_exchange = Some(parse_exchange(exchange).map_err(|e| invalid("default_exchange", e))?);
+        }
+        _ => (),
+    }
+    if let Some(aliases) = yaml["aliases"].as_hash() {
+        for (alias, symbol) in aliases {
+            match (alias.as_str(), symbol.as_str()) {
+                (Some(alias), Some(symbol)) => {
+                    let symbol: Symbol = symbol.parse().map_err(|e| invalid("alias", e))?;
+                    table.aliases.insert(alias.to_lowercase(), symbol);
+                }
+                _ => return Err(ConfigError("symbol aliases must be strings".to_string())),
+            }
+        }
+    }
+    if let Some(chats) = yaml["chat_exchanges"].as_hash() {
+        for (chat, exchange) in chats {
+            match (chat.as_str(), exchange.as_str()) {
+                (Some(chat), Some(exchange)) => {
+                    let exchange = parse_exchange(exchange).map_err(|e| invalid("chat exchange", e))?;
+                    table.chat_exchanges.insert(chat.to_string(), exchange);
+                }
+                _ => return Err(ConfigError("chat exchanges must be strings".to_string())),
+            }
+        }
+    }
+    Ok(table)
+}
+
+/// A list of ships, each written with or without the leading `~`.
+fn parse_ships(yaml: &Yaml, key: &str) -> Result<Vec<String>, ConfigError> {
+    let ships = match yaml {
+        Yaml::BadValue | Yaml::Null => return Ok(vec![]),
+        other => other
+            .as_vec()
+            .ok_or_else(|| ConfigError(format!("`{}` must be a list of ships", key)))?,
+    };
+    ships
+        .iter()
+        .map(|ship| {
+            ship.as_str()
+                .map(|ship| format!("~{}", ship.trim_start_matches('~')))
+                .ok_or_else(|| ConfigError(format!("`{}` must be a list of ships", key)))
+        })
+        .collect()
+}
+
+/// A list of groups written as `~ship/group-name`, the `~` being optional.
+fn parse_groups(yaml: &Yaml, key: &str) -> Result<Vec<String>, ConfigError> {
+    let invalid = || ConfigError(format!("`{}` must be a list of groups such as `~zod/group-name`", key));
+    let groups = match yaml {
+        Yaml::BadValue | Yaml::Null => return Ok(vec![]),
+        other => other.as_vec().ok_or_else(invalid)?,
+    };
+    groups
+        .iter()
+        .map(|group| match group.as_str().and_then(|group| group.split_once('/')) {
+            Some((ship, name)) if !name.is_empty() => Ok(format!("~{}/{}", ship.trim_start_matches('~'), name)),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+/// The `invites` section, where `setting` looks up one of its keys.
+fn parse_invites(setting: impl Fn(&str) -> Yaml) -> Result<InvitePolicy, ConfigError> {
+    let deny_ships = parse_ships(&setting("deny_ships"), "invites.deny_ships")?;
+    if let Some(ship) = deny_ships.iter().find(|ship| patp::rank(ship).is_none()) {
+        return Err(ConfigError(format!("`{}` is not a valid ship name", ship)));
+    }
+    let invalid_ranks = || ConfigError("`invites.deny_ranks` must be a list of galaxy, star, planet, moon or comet".to_string());
+    let deny_ranks = match setting("deny_ranks") {
+        Yaml::BadValue | Yaml::Null => vec![],
+        ranks => ranks
+            .as_vec()
+            .ok_or_else(invalid_ranks)?
+            .iter()
+            .map(|rank| rank.as_str().and_then(Rank::parse).ok_or_else(invalid_ranks))
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(InvitePolicy {
+        default: setting("default")
+            .as_str()
+            .and_then(InviteAction::parse)
+            .ok_or_else(|| ConfigError("`invites.default` must be accept, ignore or hold".to_string()))?,
+        allow_ships: parse_ships(&setting("allow_ships"), "invites.allow_ships")?,
+        allow_groups: parse_groups(&setting("allow_groups"), "invites.allow_groups")?,
+        deny_ships,
+        deny_ranks,
+        require_customer: setting("require_customer")
+            .as_bool()
+            .ok_or_else(|| ConfigError("`invites.require_customer` must be true or false".to_string()))?,
+        customers: parse_ships(&setting("customers"), "invites.customers")?,
+    })
+}
+
+impl BotConfig {
+    /// Parse a config from YAML. Missing keys fall back to the defaults.
+    pub fn from_yaml_str(yaml_str: &str) -> Result<BotConfig, ConfigError> {
+        let docs = YamlLoader::load_from_str(yaml_str).map_err(|e| ConfigError(e.to_string()))?;
+        let defaults = &YamlLoader::load_from_str(DEFAULT_BOT_CONFIG_YAML).unwrap()[0];
+        let config = docs.first().unwrap_or(&Yaml::Null);
+        // Look up a setting by its path, falling back to the default
+        let setting = |path: &[&str]| {
+            let lookup = |root: &Yaml| path.iter().fold(root.clone(), |yaml, key| yaml[*key].clone());
+            match lookup(config) {
+                Yaml::BadValue => lookup(defaults),
+                value => value,
+            }
+        };
+        let number = |path: &[&str]| {
+            setting(path)
+                .as_i64()
+                .filter(|n| *n >= 0)
+                .map(|n| n as u64)
+                .ok_or_else(|| ConfigError(format!("`{}` must be a positive number", path.join("."))))
+        };
+        let flag = |path: &[&str]| {
+            setting(path)
+                .as_bool()
+                .ok_or_else(|| ConfigError(format!("`{}` must be true or false", path.join("."))))
+        };
+        let text = |path: &[&str]| {
+            setting(path)
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| ConfigError(format!("`{}` must be a string", path.join("."))))
+        };
+        // An empty string turns a setting off
+        let presign_expiry = || {
+            let hours = number(&["storage", "s3", "presign_expiry_hours"])?;
+            if hours == 0 || hours * 60 * 60 > MAX_PRESIGN_EXPIRY {
+                return Err(ConfigError("`storage.s3.presign_expiry_hours` must be between 1 and 168".to_string()));
+            }
+            Ok(Duration::from_secs(hours * 60 * 60))
+        };
+        let optional_text = |path: &[&str]| Ok(Some(text(path)?).filter(|value| !value.is_empty()));
+
+        Ok(BotConfig {
+            max_indicators: number(&["max_indicators"])? as usize,
+            indicators: parse_indicators(&setting(&["indicators"]))?,
+            symbols: parse_symbols(&setting(&["symbols"]))?,
+            symbol_index: optional_text(&["symbol_index"])?,
+            browser: BrowserPoolConfig {
+                size: number(&["browser", "pool_size"])?.max(1) as usize,
+                health_check_interval: Duration::from_secs(number(&["browser", "health_check_secs"])?),
+                checkout_timeout: Duration::from_secs(number(&["browser", "checkout_timeout_secs"])?),
+                sandbox: flag(&["browser", "sandbox"])?,
+                pid_file: PathBuf::from(text(&["browser", "pid_file"])?),
+            },
+            prewarm_symbol: match flag(&["browser", "prewarm"])? {
+                true => Some(
+                    text(&["browser", "prewarm_symbol"])?
+                        .parse()
+                        .map_err(|e| ConfigError(format!("invalid `browser.prewarm_symbol`: {}", e)))?,
+                ),
+                false => None,
+            },
+            storage: match text(&["storage", "backend"])?.as_str() {
+                "s3" => StorageConfig::S3 {
+                    endpoint: optional_text(&["storage", "s3", "endpoint"])?,
+                    path_style: flag(&["storage", "s3", "path_style"])?,
+                    public_url: optional_text(&["storage", "s3", "public_url"])?,
+                    presign_expiry: presign_expiry()?,
+                },
+                "ship" => StorageConfig::Ship {
+                    path_style: flag(&["storage", "s3", "path_style"])?,
+                    public_url: optional_text(&["storage", "s3", "public_url"])?,
+                    presign_expiry: presign_expiry()?,
+                },
+                "local" => StorageConfig::Local {
+                    dir: PathBuf::from(text(&["storage", "local", "dir"])?),
+                    listen: optional_text(&["storage", "local", "listen"])?,
+                    public_url: text(&["storage", "local", "public_url"])?,
+                },
+                _ => return Err(ConfigError("`storage.backend` must be s3, ship or local".to_string())),
+            },
+            private_links: PrivateLinks {
+                everywhere: flag(&["storage", "private_everywhere"])?,
+                chats: parse_groups(&setting(&["storage", "private_chats"]), "storage.private_chats")?,
+            },
+            retention: RetentionConfig {
+                max_age: Some(number(&["retention", "max_age_days"])?)
+                    .filter(|days| *days > 0)
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+                interval: Duration::from_secs(number(&["retention", "interval_hours"])?.max(1) * 60 * 60),
+                dry_run: flag(&["retention", "dry_run"])?,
+                lifecycle_rule: flag(&["retention", "lifecycle_rule"])?,
+            },
+            workers: WorkerPoolConfig {
+                threads: number(&["workers", "threads"])?.max(1) as usize,
+                queue_per_chat: number(&["workers", "queue_per_chat"])?.max(1) as usize,
+            },
+            admins: parse_ships(&setting(&["admins"]), "admins")?,
+            welcome: Some(text(&["welcome"])?).filter(|welcome| !welcome.trim().is_empty()),
+            invites: parse_invites(|key| setting(&["invites", key]))?,
+            outbox: OutboxConfig {
+                path: PathBuf::from(text(&["outbox", "file"])?),
+                max_attempts: number(&["outbox", "max_attempts"])?.max(1) as u32,
+                retry_delay: Duration::from_secs(number(&["outbox", "retry_delay_secs"])?),
+                max_retry_delay: Duration::from_secs(number(&["outbox", "max_retry_delay_secs"])?),
+            },
+            reconnect: Backoff::new(
+                Duration::from_secs(number(&["reconnect", "initial_delay_secs"])?.max(1)),
+                Duration::from_secs(number(&["reconnect", "max_delay_secs"])?.max(1)),
+            ),
+            state_db: PathBuf::from(text(&["state_db"])?),
+        })
+    }
+
+    /// Load `bot_config.yaml`, creating it with the defaults if it does not
+    /// exist. Exits with a helpful message if the file is malformed.
+    pub fn load() -> BotConfig {
+        let path = Path::new(BOT_CONFIG_FILE);
+        if !path.exists() {
+            if let Err(e) = fs::write(path, DEFAULT_BOT_CONFIG_YAML) {
+                println!("Could not create `{}`: {}", BOT_CONFIG_FILE, e);
+            } else {
+                println!("Bot configuration file created with the defaults. Edit `{}` to customise the bot.", BOT_CONFIG_FILE);
+            }
+        }
+        let yaml_str = fs::read_to_string(path).unwrap_or_else(|_| DEFAULT_BOT_CONFIG_YAML.to_string());
+        match Self::from_yaml_str(&yaml_str) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to read `{}`: {}", BOT_CONFIG_FILE, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self::from_yaml_str(DEFAULT_BOT_CONFIG_YAML).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_parses() {
+        let config = BotConfig::default();
+        assert_eq!(config.max_indicators, 5);
+        assert_eq!(config.indicators["ema"].parameter.as_deref(), Some("length"));
+        assert_eq!(config.indicators["ema"].inputs, vec![("length".to_string(), 9.0)]);
+    }
+
+    #[test]
+    fn test_missing_keys_use_defaults() {
+        let config = BotConfig::from_yaml_str("max_indicators: 2\nbrowser:\n  pool_size: 4").unwrap();
+        assert_eq!(config.max_indicators, 2);
+        assert_eq!(config.browser.size, 4);
+        assert_eq!(config.browser.checkout_timeout, Duration::from_secs(30));
+        assert_eq!(config.workers.threads, 4);
+        assert_eq!(config.outbox.max_attempts, 8);
+        assert!(config.admins.is_empty());
+        assert_eq!(config.invites.default, InviteAction::Accept);
+        assert!(config.welcome.unwrap().contains("{bot}"));
+        assert!(BotConfig::from_yaml_str("welcome: \"\"").unwrap().welcome.is_none());
+        assert!(config.indicators.contains_key("rsi"));
+        assert_eq!(config.symbols.aliases["btc"].to_string(), "BITSTAMP:BTCUSD");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let config = BotConfig::from_yaml_str(
+            "symbols:\n  default_exchange: binance\n  chat_exchanges:\n    \"~zod/chat-1\": kraken",
+        )
+        .unwrap();
+        assert_eq!(config.symbols.default_exchange.as_deref(), Some("BINANCE"));
+        assert_eq!(config.symbols.chat_exchanges["~zod/chat-1"], "KRAKEN");
+        assert!(config.symbols.aliases.is_empty());
+        assert!(BotConfig::from_yaml_str("symbols:\n  aliases:\n    btc: \"bad&symbol\"").is_err());
+    }
+
+    #[test]
+    fn test_admins() {
+        let config = BotConfig::from_yaml_str("admins: [\"~zod\", bus]").unwrap();
+        assert_eq!(config.admins, vec!["~zod".to_string(), "~bus".to_string()]);
+        assert!(BotConfig::from_yaml_str("admins: ~zod").is_err());
+    }
+
+    #[test]
+    fn test_storage() {
+        let config = BotConfig::from_yaml_str("").unwrap();
+        assert_eq!(
+            config.storage,
+            StorageConfig::S3 {
+                endpoint: None,
+                path_style: false,
+                public_url: None,
+                presign_expiry: Duration::from_secs(24 * 60 * 60)
+            }
+        );
+        assert_eq!(config.private_links, PrivateLinks::default());
+        let config = BotConfig::from_yaml_str("storage:\n  backend: local\n  local:\n    listen: \"\"").unwrap();
+        assert_eq!(
+            config.storage,
+            StorageConfig::Local {
+                dir: PathBuf::from("charts"),
+                listen: None,
+                public_url: "http://localhost:8080".to_string()
+            }
+        );
+        assert!(BotConfig::from_yaml_str("storage:\n  backend: ftp").is_err());
+        let yaml = "storage:\n  backend: ship\n  s3:\n    presign_expiry_hours: 2\n  private_chats: [\"zod/premium\"]";
+        let config = BotConfig::from_yaml_str(yaml).unwrap();
+        assert_eq!(
+            config.storage,
+            StorageConfig::Ship {
+                path_style: false,
+                public_url: None,
+                presign_expiry: Duration::from_secs(2 * 60 * 60)
+            }
+        );
+        assert_eq!(config.private_links.chats, vec!["~zod/premium".to_string()]);
+        assert!(BotConfig::from_yaml_str("storage:\n  s3:\n    presign_expiry_hours: 200").is_err());
+        assert!(BotConfig::from_yaml_str("storage:\n  s3:\n    presign_expiry_hours: 0").is_err());
+        assert!(BotConfig::from_yaml_str("storage:\n  private_chats: [premium]").is_err());
+    }
+
+    #[test]
+    fn test_retention() {
+        let config = BotConfig::from_yaml_str("").unwrap();
+        assert_eq!(config.retention.max_age, None);
+        assert_eq!(config.retention.interval, Duration::from_secs(24 * 60 * 60));
+        let config = BotConfig::from_yaml_str("retention:\n  max_age_days: 30\n  dry_run: true").unwrap();
+        assert_eq!(config.retention.max_age, Some(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert!(config.retention.dry_run);
+        assert!(!config.retention.lifecycle_rule);
+    }
+
+    #[test]
+    fn test_invites() {
+        let yaml = "invites:\n  default: hold\n  allow_groups: [\"zod/traders\"]\n  deny_ships: [\"~marzod\", \"dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod\"]\n  deny_ranks: [star, galaxy, comet]";
+        let invites = BotConfig::from_yaml_str(yaml).unwrap().invites;
+        assert_eq!(invites.default, InviteAction::Hold);
+        assert_eq!(invites.allow_groups, vec!["~zod/traders".to_string()]);
+        let comet = "~dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod".to_string();
+        assert_eq!(invites.deny_ships, vec!["~marzod".to_string(), comet]);
+        assert_eq!(invites.deny_ranks, vec![Rank::Star, Rank::Galaxy, Rank::Comet]);
+        assert!(BotConfig::from_yaml_str("invites:\n  deny_ranks: [king]").is_err());
+        assert!(!invites.require_customer);
+        assert!(BotConfig::from_yaml_str("invites:\n  default: maybe").is_err());
+        assert!(BotConfig::from_yaml_str("invites:\n  deny_ships: [\"~notaship\"]").is_err());
+        assert!(BotConfig::from_yaml_str("invites:\n  allow_groups: [\"~zod\"]").is_err());
+    }
+}
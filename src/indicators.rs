@@ -0,0 +1,142 @@
+use json::JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+
+/// An indicator users may add to a chart, as listed in the bot config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndicatorConfig {
+    /// TradingView study id, e.g. `RSI@tv-basicstudies`.
+    pub study_id: String,
+    /// Default inputs passed to the study.
+    pub inputs: Vec<(String, f64)>,
+    /// The input set by the value in `ema:200`, if the indicator takes one.
+    pub parameter: Option<String>,
+}
+
+/// Allowed indicators keyed by the name users type, e.g. `rsi`.
+pub type IndicatorTable = HashMap<String, IndicatorConfig>;
+
+/// A study to draw on a chart, with its inputs resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Study {
+    pub study_id: String,
+    pub inputs: Vec<(String, f64)>,
+}
+
+/// An indicator was recognised but its value is invalid.
+#[derive(Debug, PartialEq)]
+pub struct IndicatorError(String);
+
+impl fmt::Display for IndicatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IndicatorError {}
+
+impl Study {
+    /// The study as the TradingView widget expects it in `studies`.
+    pub fn to_json(&self) -> JsonValue {
+        let mut study = JsonValue::new_object();
+        study["id"] = self.study_id.as_str().into();
+        if !self.inputs.is_empty() {
+            let mut inputs = JsonValue::new_object();
+            for (name, value) in &self.inputs {
+                inputs[name.as_str()] = (*value).into();
+            }
+            study["inputs"] = inputs;
+        }
+        study
+    }
+}
+
+/// The widget's `studies` parameter for a list of studies.
+pub fn studies_param(studies: &[Study]) -> String {
+    JsonValue::Array(studies.iter().map(Study::to_json).collect()).dump()
+}
+
+/// Parse a word such as `rsi` or `ema:200` into a `Study`. Returns `Ok(None)`
+/// if the name is not an allowed indicator.
+pub fn parse_indicator(word: &str, table: &IndicatorTable) -> Result<Option<Study>, IndicatorError> {
+    let lowercase = word.to_lowercase();
+    let (name, value) = match lowercase.split_once(':') {
+        Some((name, value)) => (name, Some(value)),
+        None => (lowercase.as_str(), None),
+    };
+    let indicator = match table.get(name) {
+        Some(indicator) => indicator,
+        None => return Ok(None),
+    };
+
+    let mut inputs = indicator.inputs.clone();
+    if let Some(value) = value {
+        let parameter = indicator
+            .parameter
+            .as_ref()
+            .ok_or_else(|| IndicatorError(format!("Indicator `{}` does not take a value.", name)))?;
+        let value: f64 = value
+            .parse()
+            .ok()
+            .filter(|value: &f64| value.is_finite() && *value > 0.0)
+            .ok_or_else(|| IndicatorError(format!("Invalid value `{}` for indicator `{}`.", value, name)))?;
+        match inputs.iter_mut().find(|(input, _)| input == parameter) {
+            Some(input) => input.1 = value,
+            None => inputs.push((parameter.clone(), value)),
+        }
+    }
+
+    Ok(Some(Study {
+        study_id: indicator.study_id.clone(),
+        inputs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> IndicatorTable {
+        let mut table = IndicatorTable::new();
+        table.insert(
+            "ema".to_string(),
+            IndicatorConfig {
+                study_id: "MAExp@tv-basicstudies".to_string(),
+                inputs: vec![("length".to_string(), 9.0)],
+                parameter: Some("length".to_string()),
+            },
+        );
+        table.insert(
+            "macd".to_string(),
+            IndicatorConfig {
+                study_id: "MACD@tv-basicstudies".to_string(),
+                inputs: vec![],
+                parameter: None,
+            },
+        );
+        table
+    }
+
+    #[test]
+    fn test_parse_indicator() {
+        let ema = parse_indicator("EMA:200", &table()).unwrap().unwrap();
+        assert_eq!(ema.inputs, vec![("length".to_string(), 200.0)]);
+        let ema = parse_indicator("ema", &table()).unwrap().unwrap();
+        assert_eq!(ema.inputs, vec![("length".to_string(), 9.0)]);
+        assert_eq!(parse_indicator("rsi", &table()), Ok(None));
+        assert!(parse_indicator("macd:12", &table()).is_err());
+        assert!(parse_indicator("ema:-5", &table()).is_err());
+    }
+
+    #[test]
+    fn test_studies_param() {
+        let studies = vec![
+            parse_indicator("macd", &table()).unwrap().unwrap(),
+            parse_indicator("ema:200", &table()).unwrap().unwrap(),
+        ];
+        assert_eq!(
+            studies_param(&studies),
+            r#"[{"id":"MACD@tv-basicstudies"},{"id":"MAExp@tv-basicstudies","inputs":{"length":200}}]"#
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A chart interval, e.g. `4h` or `1D`.
+///
+/// Intervals are written as an optional number followed by a unit, with
+/// optional spaces in between (`4h`, `4 hours`, `daily`). The grammar has a
+/// few fixed rules so that every input has exactly one meaning:
+///
+/// - A bare number is always a number of minutes (`15`, `240`), as on TradingView.
+/// - `m` is always minutes. Months are written `mo`, `mth` or `month`, or with
+///   TradingView's own uppercase `M` (`1M`, `3M`).
+/// - Years are twelve months (`1y` is `12M`).
+/// - Equivalent intervals are normalised, so `60m`, `1h` and `60` are all `1h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interval {
+    Seconds(u32),
+    Minutes(u32),
+    Hours(u32),
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+}
+
+/// Why a string could not be read as an `Interval`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IntervalError {
+    /// The input does not follow the interval grammar.
+    Unknown(String),
+    /// The input is a valid interval, but TradingView has no chart for it.
+    Unsupported(Interval),
+}
+
+impl fmt::Display for IntervalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntervalError::Unknown(input) => write!(
+                f,
+                "Unknown timeframe `{}`. Supported timeframes: {}",
+                input,
+                supported_list()
+            ),
+            IntervalError::Unsupported(interval) => write!(
+                f,
+                "Timeframe `{}` is not available on TradingView. Supported timeframes: {}",
+                interval,
+                supported_list()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntervalError {}
+
+/// Every interval TradingView can chart, in ascending order.
+pub const SUPPORTED_INTERVALS: &[Interval] = &[
+    Interval::Seconds(1),
+    Interval::Seconds(5),
+    Interval::Seconds(15),
+    Interval::Seconds(30),
+    Interval::Minutes(1),
+    Interval::Minutes(3),
+    Interval::Minutes(5),
+    Interval::Minutes(15),
+    Interval::Minutes(30),
+    Interval::Minutes(45),
+    Interval::Hours(1),
+    Interval::Hours(2),
+    Interval::Hours(3),
+    Interval::Hours(4),
+    Interval::Days(1),
+    Interval::Days(2),
+    Interval::Days(3),
+    Interval::Weeks(1),
+    Interval::Weeks(2),
+    Interval::Weeks(3),
+    Interval::Months(1),
+    Interval::Months(2),
+    Interval::Months(3),
+    Interval::Months(6),
+    Interval::Months(12),
+];
+
+fn supported_list() -> String {
+    SUPPORTED_INTERVALS
+        .iter()
+        .map(|interval| interval.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl Interval {
+    /// The value TradingView's widget expects in its `interval` parameter.
+    pub fn to_tradingview(self) -> String {
+        match self {
+            Interval::Seconds(n) => format!("{}S", n),
+            Interval::Minutes(n) => n.to_string(),
+            Interval::Hours(n) => (n * 60).to_string(),
+            Interval::Days(1) => "D".to_string(),
+            Interval::Days(n) => format!("{}D", n),
+            Interval::Weeks(1) => "W".to_string(),
+            Interval::Weeks(n) => format!("{}W", n),
+            Interval::Months(1) => "M".to_string(),
+            Interval::Months(n) => format!("{}M", n),
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        SUPPORTED_INTERVALS.contains(&self)
+    }
+
+    /// Rewrite the interval in the largest unit that divides it evenly.
+    fn normalize(self) -> Interval {
+        match self {
+            Interval::Seconds(n) if n % 60 == 0 => Interval::Minutes(n / 60).normalize(),
+            Interval::Minutes(n) if n % 60 == 0 => Interval::Hours(n / 60).normalize(),
+            Interval::Hours(n) if n % 24 == 0 => Interval::Days(n / 24).normalize(),
+            Interval::Days(n) if n % 7 == 0 => Interval::Weeks(n / 7),
+            other => other,
+        }
+    }
+
+    /// Parse an interval that may be split over two words, like `4 h`.
+    /// Returns the interval and how many of `words` it used.
+    pub fn parse_words(words: &[String]) -> Result<(Interval, usize), IntervalError> {
+        let first = match words.first() {
+            Some(word) => word,
+            None => return Err(IntervalError::Unknown(String::new())),
+        };
+        if let Some(second) = words.get(1) {
+            let is_number = first.chars().all(|c| c.is_ascii_digit());
+            let is_unit = second.chars().all(|c| c.is_alphabetic());
+            if is_number && is_unit {
+                if let Ok(interval) = format!("{}{}", first, second).parse() {
+                    return Ok((interval, 2));
+                }
+            }
+        }
+        Ok((first.parse()?, 1))
+    }
+}
+
+/// Builds an `Interval` of one unit from a count.
+type UnitConstructor = fn(u32) -> Interval;
+
+/// Map a unit word to the constructor for that unit and a multiplier.
+fn unit_from_word(unit: &str) -> Option<(UnitConstructor, u32)> {
+    // TradingView's uppercase `M` is the only case-sensitive unit.
+    if unit == "M" {
+        return Some((Interval::Months, 1));
+    }
+    let unit: (UnitConstructor, u32) = match unit.to_lowercase().as_str() {
+        "" | "m" | "min" | "mins" | "minute" | "minutes" => (Interval::Minutes, 1),
+        "s" | "sec" | "secs" | "second" | "seconds" => (Interval::Seconds, 1),
+        "h" | "hr" | "hrs" | "hour" | "hours" | "hourly" => (Interval::Hours, 1),
+        "d" | "day" | "days" | "daily" => (Interval::Days, 1),
+        "w" | "wk" | "wks" | "week" | "weeks" | "weekly" => (Interval::Weeks, 1),
+        "mo" | "mos" | "mth" | "mths" | "month" | "months" | "monthly" => (Interval::Months, 1),
+        "y" | "yr" | "yrs" | "year" | "years" | "yearly" | "annual" | "annually" => (Interval::Months, 12),
+        _ => return None,
+    };
+    Some(unit)
+}
+
+impl FromStr for Interval {
+    type Err = IntervalError;
+
+    fn from_str(input: &str) -> Result<Interval, IntervalError> {
+        let unknown = || IntervalError::Unknown(input.to_string());
+        let compact: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+        let digits_end = compact
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(compact.len());
+        let (number, unit) = compact.split_at(digits_end);
+        if number.is_empty() && unit.is_empty() {
+            return Err(unknown());
+        }
+
+        let count: u32 = match number {
+            "" => 1,
+            digits => digits.parse().map_err(|_| unknown())?,
+        };
+        let (constructor, multiplier) = unit_from_word(unit).ok_or_else(unknown)?;
+        let count = count.checked_mul(multiplier).ok_or_else(unknown)?;
+        if count == 0 {
+            return Err(unknown());
+        }
+
+        let interval = constructor(count).normalize();
+        if interval.is_supported() {
+            Ok(interval)
+        } else {
+            Err(IntervalError::Unsupported(interval))
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interval::Seconds(n) => write!(f, "{}s", n),
+            Interval::Minutes(n) => write!(f, "{}m", n),
+            Interval::Hours(n) => write!(f, "{}h", n),
+            Interval::Days(n) => write!(f, "{}D", n),
+            Interval::Weeks(n) => write!(f, "{}W", n),
+            Interval::Months(n) => write!(f, "{}M", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Interval, IntervalError> {
+        input.parse()
+    }
+
+    #[test]
+    fn test_units_and_aliases() {
+        assert_eq!(parse("30s"), Ok(Interval::Seconds(30)));
+        assert_eq!(parse("45m"), Ok(Interval::Minutes(45)));
+        assert_eq!(parse("4 hours"), Ok(Interval::Hours(4)));
+        assert_eq!(parse("daily"), Ok(Interval::Days(1)));
+        assert_eq!(parse("2D"), Ok(Interval::Days(2)));
+        assert_eq!(parse("3W"), Ok(Interval::Weeks(3)));
+        assert_eq!(parse("3M"), Ok(Interval::Months(3)));
+        assert_eq!(parse("1y"), Ok(Interval::Months(12)));
+    }
+
+    #[test]
+    fn test_ambiguity_rules() {
+        // Bare numbers are minutes
+        assert_eq!(parse("1"), Ok(Interval::Minutes(1)));
+        assert_eq!(parse("240"), Ok(Interval::Hours(4)));
+        // Lowercase m is minutes, months need `mo` or uppercase `M`
+        assert_eq!(parse("1m"), Ok(Interval::Minutes(1)));
+        assert_eq!(parse("1mo"), Ok(Interval::Months(1)));
+        assert_eq!(parse("1M"), Ok(Interval::Months(1)));
+        // Equivalent intervals are normalised
+        assert_eq!(parse("60m"), Ok(Interval::Hours(1)));
+        assert_eq!(parse("1440"), Ok(Interval::Days(1)));
+        assert_eq!(parse("7d"), Ok(Interval::Weeks(1)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("banana"), Err(IntervalError::Unknown("banana".to_string())));
+        assert_eq!(parse("0h"), Err(IntervalError::Unknown("0h".to_string())));
+        assert_eq!(parse("7h"), Err(IntervalError::Unsupported(Interval::Hours(7))));
+    }
+
+    #[test]
+    fn test_to_tradingview() {
+        assert_eq!(Interval::Seconds(5).to_tradingview(), "5S");
+        assert_eq!(Interval::Hours(4).to_tradingview(), "240");
+        assert_eq!(Interval::Days(1).to_tradingview(), "D");
+        assert_eq!(Interval::Weeks(3).to_tradingview(), "3W");
+        assert_eq!(Interval::Months(12).to_tradingview(), "12M");
+    }
+
+    #[test]
+    fn test_parse_words() {
+        let words: Vec<String> = vec!["4".to_string(), "h".to_string(), "rsi".to_string()];
+        assert_eq!(Interval::parse_words(&words), Ok((Interval::Hours(4), 2)));
+        let words: Vec<String> = vec!["15".to_string(), "rsi".to_string()];
+        assert_eq!(Interval::parse_words(&words), Ok((Interval::Minutes(15), 1)));
+    }
+}
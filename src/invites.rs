@@ -0,0 +1,299 @@
+use json::{object, JsonValue};
+use std::convert::TryInto;
+use tokio::sync::Notify;
+
+use crate::outbox::unix_now;
+use crate::patp::{self, Rank};
+use crate::state_store::{StateStore, StoreError};
+
+/// What to do with a group invite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InviteAction {
+    Accept,
+    /// Leave the invite unanswered.
+    Ignore,
+    /// Keep the invite until an admin approves it with `approve <id>`.
+    Hold,
+}
+
+impl InviteAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "accept" => Some(InviteAction::Accept),
+            "ignore" => Some(InviteAction::Ignore),
+            "hold" => Some(InviteAction::Hold),
+            _ => None,
+        }
+    }
+}
+
+/// Rules deciding which group invites the bot accepts. Denied ships win over
+/// allowed ones, and allowed ships and groups skip the customer check.
+#[derive(Clone, Debug)]
+pub struct InvitePolicy {
+    /// What to do with invites no rule matches.
+    pub default: InviteAction,
+    /// Ships whose invites are accepted, written as `~ship`.
+    pub allow_ships: Vec<String>,
+    /// Groups that are joined when invited, written as `~ship/group-name`.
+    pub allow_groups: Vec<String>,
+    /// Ships whose invites and groups are ignored, written as `~ship`.
+    pub deny_ships: Vec<String>,
+    /// Ranks of ships whose invites and groups are ignored, e.g. stars.
+    pub deny_ranks: Vec<Rank>,
+    /// Ignore invites from ships that never paid the bot.
+    pub require_customer: bool,
+    /// Ships that count as customers without a payment on record.
+    pub customers: Vec<String>,
+}
+
+impl InvitePolicy {
+    /// What to do with `invite`, given whether the inviter is a customer.
+    pub fn decide(&self, invite: &Invite, is_customer: bool) -> InviteAction {
+        let denied = |ship: &str| {
+            self.deny_ships.iter().any(|denied| denied == ship)
+                || patp::rank(ship).is_some_and(|rank| self.deny_ranks.contains(&rank))
+        };
+        if denied(&invite.inviter) || denied(&invite.host) {
+            InviteAction::Ignore
+        } else if self.allow_ships.contains(&invite.inviter) || self.allow_groups.contains(&invite.group()) {
+            InviteAction::Accept
+        } else if self.require_customer && !is_customer {
+            InviteAction::Ignore
+        } else {
+            self.default
+        }
+    }
+}
+
+/// An invite to a group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invite {
+    /// The ship that sent the invite, as `~ship`.
+    pub inviter: String,
+    /// The ship hosting the group, as `~ship`.
+    pub host: String,
+    pub name: String,
+    /// Unix time the invite arrived.
+    pub received_at: u64,
+}
+
+fn ship_name(json: &JsonValue) -> Option<String> {
+    Some(format!("~{}", json.as_str()?.trim_start_matches('~')))
+}
+
+impl Invite {
+    /// The invite in an `invite-update` from invite-store, if it is a new one.
+    pub fn from_update(update: &JsonValue) -> Option<Self> {
+        let invite = &update["invite-update"]["invite"]["invite"];
+        Some(Invite {
+            inviter: ship_name(&invite["ship"])?,
+            host: ship_name(&invite["resource"]["ship"])?,
+            name: invite["resource"]["name"].as_str()?.to_string(),
+            received_at: unix_now(),
+        })
+    }
+
+    /// The group written as `~ship/group-name`.
+    pub fn group(&self) -> String {
+        format!("{}/{}", self.host, self.name)
+    }
+
+    fn to_json(&self, approved: bool) -> JsonValue {
+        object! {
+            "inviter": self.inviter.clone(),
+            "host": self.host.clone(),
+            "name": self.name.clone(),
+            "received_at": self.received_at,
+            "approved": approved,
+        }
+    }
+
+    fn from_bytes(value: &[u8]) -> Option<(Self, bool)> {
+        let json = json::parse(std::str::from_utf8(value).ok()?).ok()?;
+        let invite = Invite {
+            inviter: json["inviter"].as_str()?.to_string(),
+            host: json["host"].as_str()?.to_string(),
+            name: json["name"].as_str()?.to_string(),
+            received_at: json["received_at"].as_u64().unwrap_or_default(),
+        };
+        Some((invite, json["approved"].as_bool().unwrap_or_default()))
+    }
+}
+
+/// Ships that paid into the bot's wallet according to a btc-wallet update.
+fn paying_ships(update: &JsonValue) -> Vec<String> {
+    let transactions = std::iter::once(&update["new-tx"]).chain(update["initial"]["history"].entries().map(|(_, tx)| tx));
+    transactions
+        // Only payments the bot received
+        .filter(|tx| tx["sent"].as_bool() == Some(false))
+        .flat_map(|tx| tx["inputs"].members())
+        .filter_map(|input| ship_name(&input["val"]["ship"]))
+        .collect()
+}
+
+/// The invite policy along with the invites held for approval and the
+/// customers seen in the wallet, both kept in the state database.
+pub struct Invites {
+    policy: InvitePolicy,
+    held: sled::Tree,
+    customers: sled::Tree,
+    approved: Notify,
+}
+
+impl Invites {
+    pub fn new(policy: InvitePolicy, store: &StateStore) -> Result<Self, StoreError> {
+        Ok(Invites {
+            policy,
+            held: store.tree("held_invites")?,
+            customers: store.tree("customers")?,
+            approved: Notify::new(),
+        })
+    }
+
+    /// What to do with `invite` under the policy.
+    pub fn decide(&self, invite: &Invite) -> InviteAction {
+        let is_customer = self.policy.customers.contains(&invite.inviter)
+            || self.customers.contains_key(&invite.inviter).unwrap_or_default();
+        self.policy.decide(invite, is_customer)
+    }
+
+    /// Remember the ships that paid the bot in a btc-wallet update.
+    pub fn record_payments(&self, wallet_update: &str) {
+        let update = match json::parse(wallet_update) {
+            Ok(update) => update,
+            Err(_) => return,
+        };
+        for ship in paying_ships(&update) {
+            println!("Recorded a payment from {}.", ship);
+            if let Err(e) = self.customers.insert(&ship, &unix_now().to_be_bytes()) {
+                println!("Failed to record the payment from {}: {}", ship, e);
+            }
+        }
+    }
+
+    /// Keep `invite` until an admin approves it, returning its id.
+    pub fn hold(&self, invite: &Invite) -> Result<u64, StoreError> {
+        let last_id = self.held.last()?.and_then(|(id, _)| id.as_ref().try_into().ok()).map(u64::from_be_bytes);
+        let id = last_id.unwrap_or_default() + 1;
+        self.held.insert(id.to_be_bytes(), invite.to_json(false).dump().as_bytes())?;
+        Ok(id)
+    }
+
+    /// Invites waiting for approval, oldest first.
+    pub fn held(&self) -> Vec<(u64, Invite)> {
+        self.held
+            .iter()
+            .filter_map(Result::ok)
+            .filter_map(|(id, value)| {
+                let id = u64::from_be_bytes(id.as_ref().try_into().ok()?);
+                match Invite::from_bytes(&value)? {
+                    (invite, false) => Some((id, invite)),
+                    (_, true) => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Approve the held invite `id`, so the bot accepts it. Returns the
+    /// invite, or None if there is no such invite.
+    pub fn approve(&self, id: u64) -> Result<Option<Invite>, StoreError> {
+        let invite = match self.held.get(id.to_be_bytes())?.and_then(|value| Invite::from_bytes(&value)) {
+            Some((invite, false)) => invite,
+            _ => return Ok(None),
+        };
+        self.held.insert(id.to_be_bytes(), invite.to_json(true).dump().as_bytes())?;
+        self.approved.notify_one();
+        Ok(Some(invite))
+    }
+
+    /// Wait until an invite is approved.
+    pub async fn approved(&self) {
+        self.approved.notified().await
+    }
+
+    /// Remove and return the approved invites.
+    pub fn take_approved(&self) -> Vec<Invite> {
+        let mut approved = vec![];
+        for (id, value) in self.held.iter().filter_map(Result::ok) {
+            if let Some((invite, true)) = Invite::from_bytes(&value) {
+                self.held.remove(id).ok();
+                approved.push(invite);
+            }
+        }
+        approved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(inviter: &str, host: &str) -> Invite {
+        Invite {
+            inviter: inviter.to_string(),
+            host: host.to_string(),
+            name: "traders".to_string(),
+            received_at: 0,
+        }
+    }
+
+    fn policy() -> InvitePolicy {
+        InvitePolicy {
+            default: InviteAction::Hold,
+            allow_ships: vec!["~dapnep-ronmyl".to_string(), "~sampel-palnet".to_string()],
+            allow_groups: vec!["~lagrev-nocfep/traders".to_string()],
+            deny_ships: vec!["~sampel-palnet".to_string(), "~dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod".to_string()],
+            deny_ranks: vec![Rank::Galaxy, Rank::Star],
+            require_customer: true,
+            customers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = policy();
+        assert_eq!(policy.decide(&invite("~dapnep-ronmyl", "~dostec-risfen"), false), InviteAction::Accept);
+        // Denied ships win over allowed ones
+        assert_eq!(policy.decide(&invite("~sampel-palnet", "~dostec-risfen"), false), InviteAction::Ignore);
+        assert_eq!(policy.decide(&invite("~ridlur-figbud", "~lagrev-nocfep"), false), InviteAction::Accept);
+        let comet = "~dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod";
+        assert_eq!(policy.decide(&invite(comet, "~lagrev-nocfep"), false), InviteAction::Ignore);
+        let comets = InvitePolicy { deny_ships: vec![], deny_ranks: vec![Rank::Comet], ..policy.clone() };
+        assert_eq!(comets.decide(&invite(comet, "~lagrev-nocfep"), false), InviteAction::Ignore);
+        assert_eq!(comets.decide(&invite("~ridlur-figbud", "~lagrev-nocfep"), false), InviteAction::Accept);
+        // Stars and galaxies are denied
+        assert_eq!(policy.decide(&invite("~dapnep-ronmyl", "~zod"), false), InviteAction::Ignore);
+        assert_eq!(policy.decide(&invite("~marzod", "~dostec-risfen"), true), InviteAction::Ignore);
+        assert_eq!(policy.decide(&invite("~ridlur-figbud", "~dostec-risfen"), false), InviteAction::Ignore);
+        assert_eq!(policy.decide(&invite("~ridlur-figbud", "~dostec-risfen"), true), InviteAction::Hold);
+    }
+
+    #[test]
+    fn test_held_invites_wait_for_approval() {
+        let store = StateStore::temporary();
+        let invites = Invites::new(policy(), &store).unwrap();
+        let first = invites.hold(&invite("~nec", "~bus")).unwrap();
+        let second = invites.hold(&invite("~wes", "~bus")).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(invites.held().len(), 2);
+
+        assert_eq!(invites.approve(first).unwrap().map(|invite| invite.inviter), Some("~nec".to_string()));
+        assert!(invites.approve(first).unwrap().is_none());
+        assert!(invites.approve(99).unwrap().is_none());
+        assert_eq!(invites.held(), vec![(second, invite("~wes", "~bus"))]);
+        assert_eq!(invites.take_approved(), vec![invite("~nec", "~bus")]);
+        assert!(invites.take_approved().is_empty());
+    }
+
+    #[test]
+    fn test_payments_make_customers() {
+        let store = StateStore::temporary();
+        let invites = Invites::new(policy(), &store).unwrap();
+        assert_eq!(invites.decide(&invite("~ridlur-figbud", "~dostec-risfen")), InviteAction::Ignore);
+        invites.record_payments(r#"{"new-tx": {"sent": false, "inputs": [{"val": {"ship": "~ridlur-figbud"}}]}}"#);
+        invites.record_payments(r#"{"new-tx": {"sent": true, "inputs": [{"val": {"ship": "~lagrev-nocfep"}}]}}"#);
+        assert_eq!(invites.decide(&invite("~ridlur-figbud", "~dostec-risfen")), InviteAction::Hold);
+        assert_eq!(invites.decide(&invite("~lagrev-nocfep", "~dostec-risfen")), InviteAction::Ignore);
+    }
+}
@@ -1,362 +1,151 @@
 extern crate s3;
 
 use bot::ShipChat;
-use dotenv::dotenv;
-use headless_chrome::{
-    protocol::{page::ScreenshotFormat, target::methods::CreateTarget},
-    Browser,
-};
-use std::env;
-use time::Instant;
-
-use s3::bucket::Bucket;
-use s3::creds::Credentials;
-use s3::region::Region;
-
-use chrono;
-use std::{thread, time};
+use browser_pool::BrowserPool;
+use chart_request::ChartRequest;
+use commands::approve::ApproveCommand;
+use commands::chart::ChartCommand;
+use commands::outbox::OutboxCommand;
+use commands::search::SearchCommand;
+use commands::{CommandContext, CommandRegistry};
+use config::BotConfig;
+use interval::Interval;
+use invites::Invites;
+use outbox::Outbox;
+use state_store::StateStore;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use symbol_index::SymbolIndex;
 
 mod bot;
-
-struct Timeframe {
-    parsable_phrases: Vec<String>,
-    parsed: String,
+mod browser_pool;
+mod chart_request;
+mod commands;
+mod config;
+mod indicators;
+mod interval;
+mod invites;
+mod outbox;
+mod patp;
+mod processed;
+mod range;
+mod retention;
+mod screenshot;
+mod ship_events;
+mod state_store;
+mod storage;
+mod style;
+mod symbol_index;
+mod symbols;
+#[cfg(test)]
+mod testing;
+mod welcome;
+mod workers;
+
+/// The bot's settings, loaded from `bot_config.yaml` on first use.
+fn config() -> &'static BotConfig {
+    static CONFIG: OnceLock<BotConfig> = OnceLock::new();
+    CONFIG.get_or_init(BotConfig::load)
 }
 
-fn build_timeframe(parsable_phrases: Vec<String>, parsed: String) -> Timeframe {
-    Timeframe {
-        parsable_phrases: parsable_phrases,
-        parsed: parsed,
-    }
+/// Replies waiting to be sent, shared by the bot and the `outbox` command.
+fn outbox() -> Arc<Outbox> {
+    static OUTBOX: OnceLock<Arc<Outbox>> = OnceLock::new();
+    let outbox = OUTBOX.get_or_init(|| match Outbox::load(config().outbox.clone()) {
+        Ok(outbox) => Arc::new(outbox),
+        Err(e) => {
+            println!("Failed to read the outbox `{}`: {}", config().outbox.path.display(), e);
+            std::process::exit(1);
+        }
+    });
+    Arc::clone(outbox)
 }
 
-fn parse_timeframe(phrase: String) -> String {
-    let timeframes = [
-        build_timeframe(
-            vec![
-                "1".to_string(),
-                "1m".to_string(),
-                "1min".to_string(),
-                "1mins".to_string(),
-                "1minute".to_string(),
-                "1minutes".to_string(),
-                "min".to_string(),
-                "m".to_string(),
-            ],
-            "1".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "3".to_string(),
-                "3m".to_string(),
-                "3min".to_string(),
-                "3mins".to_string(),
-                "3minute".to_string(),
-                "3minutes".to_string(),
-            ],
-            "3".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "5".to_string(),
-                "5m".to_string(),
-                "5min".to_string(),
-                "5mins".to_string(),
-                "5minute".to_string(),
-                "5minutes".to_string(),
-            ],
-            "5".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "15".to_string(),
-                "15m".to_string(),
-                "15min".to_string(),
-                "15mins".to_string(),
-                "15minute".to_string(),
-                "15minutes".to_string(),
-            ],
-            "15".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "30".to_string(),
-                "30m".to_string(),
-                "30min".to_string(),
-                "30mins".to_string(),
-                "30minute".to_string(),
-                "30minutes".to_string(),
-            ],
-            "30".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "60".to_string(),
-                "60m".to_string(),
-                "60min".to_string(),
-                "60mins".to_string(),
-                "60minute".to_string(),
-                "60minutes".to_string(),
-                "1".to_string(),
-                "1h".to_string(),
-                "1hr".to_string(),
-                "1hour".to_string(),
-                "1hours".to_string(),
-                "hourly".to_string(),
-                "hour".to_string(),
-                "hr".to_string(),
-                "h".to_string(),
-            ],
-            "60".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "120".to_string(),
-                "120m".to_string(),
-                "120min".to_string(),
-                "120mins".to_string(),
-                "120minute".to_string(),
-                "120minutes".to_string(),
-                "2".to_string(),
-                "2h".to_string(),
-                "2hr".to_string(),
-                "2hrs".to_string(),
-                "2hour".to_string(),
-                "2hours".to_string(),
-            ],
-            "120".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "180".to_string(),
-                "180m".to_string(),
-                "180min".to_string(),
-                "180mins".to_string(),
-                "180minute".to_string(),
-                "180minutes".to_string(),
-                "3".to_string(),
-                "3h".to_string(),
-                "3hr".to_string(),
-                "3hrs".to_string(),
-                "3hour".to_string(),
-                "3hours".to_string(),
-            ],
-            "180".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "240".to_string(),
-                "240m".to_string(),
-                "240min".to_string(),
-                "240mins".to_string(),
-                "240minute".to_string(),
-                "240minutes".to_string(),
-                "4".to_string(),
-                "4h".to_string(),
-                "4hr".to_string(),
-                "4hrs".to_string(),
-                "4hour".to_string(),
-                "4hours".to_string(),
-            ],
-            "240".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "24".to_string(),
-                "24h".to_string(),
-                "24hr".to_string(),
-                "24hrs".to_string(),
-                "24hour".to_string(),
-                "24hours".to_string(),
-                "d".to_string(),
-                "day".to_string(),
-                "1".to_string(),
-                "1d".to_string(),
-                "1day".to_string(),
-                "daily".to_string(),
-                "1440".to_string(),
-                "1440m".to_string(),
-                "1440min".to_string(),
-                "1440mins".to_string(),
-                "1440minute".to_string(),
-                "1440minutes".to_string(),
-            ],
-            "D".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "7".to_string(),
-                "7d".to_string(),
-                "7day".to_string(),
-                "7days".to_string(),
-                "w".to_string(),
-                "week".to_string(),
-                "1w".to_string(),
-                "1week".to_string(),
-                "weekly".to_string(),
-            ],
-            "W".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "30d".to_string(),
-                "30day".to_string(),
-                "30days".to_string(),
-                "1".to_string(),
-                "1m".to_string(),
-                "m".to_string(),
-                "mo".to_string(),
-                "month".to_string(),
-                "1mo".to_string(),
-                "1month".to_string(),
-                "monthly".to_string(),
-            ],
-            "M".to_string(),
-        ),
-        build_timeframe(
-            vec![
-                "12".to_string(),
-                "12m".to_string(),
-                "12mo".to_string(),
-                "12month".to_string(),
-                "12months".to_string(),
-                "year".to_string(),
-                "yearly".to_string(),
-                "1year".to_string(),
-                "1y".to_string(),
-                "y".to_string(),
-                "annual".to_string(),
-                "annually".to_string(),
-            ],
-            "Y".to_string(),
-        ),
-    ];
-
-    for tf in timeframes {
-        if tf.parsable_phrases.contains(&phrase) {
-            return tf.parsed;
+/// The bot's persistent state.
+fn store() -> Arc<StateStore> {
+    static STORE: OnceLock<Arc<StateStore>> = OnceLock::new();
+    let store = STORE.get_or_init(|| match StateStore::open(&config().state_db) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            println!("Failed to open the state database `{}`: {}", config().state_db.display(), e);
+            std::process::exit(1);
         }
-    }
-
-    "1".to_string()
+    });
+    Arc::clone(store)
 }
 
-fn screenshot_tab(url: &str, width: u16, height: u16) -> Result<Vec<u8>, failure::Error> {
-    let browser = Browser::default()?;
-    let tab = browser.new_tab_with_options(CreateTarget {
-        url: url,
-        width: Some(width.into()),
-        height: Some(height.into()),
-        browser_context_id: None,
-        enable_begin_frame_control: None,
-    })?;
-    tab.navigate_to(url)?;
-    tab.wait_until_navigated()?;
-
-    tab.wait_for_element(".chart-gui-wrapper > canvas")?;
-    let legend = tab.wait_for_element("[data-name='legend-series-item']")?;
-
-    let is_available = legend
-        .call_js_fn(
-            r#"
-        function containsNA () {
-            return !this.innerText.includes("n/a");
+/// The invite policy and the invites held for approval, shared by the bot
+/// and the `approve` command.
+fn invites() -> Arc<Invites> {
+    static INVITES: OnceLock<Arc<Invites>> = OnceLock::new();
+    let invites = INVITES.get_or_init(|| match Invites::new(config().invites.clone(), &store()) {
+        Ok(invites) => Arc::new(invites),
+        Err(e) => {
+            println!("Failed to open the held invites: {}", e);
+            std::process::exit(1);
         }
-    "#,
-            false,
-        )
-        .unwrap()
-        .value;
-
-    match is_available.eq(&Some(serde_json::value::Value::Bool(true))) {
-        true => Ok(tab.capture_screenshot(ScreenshotFormat::PNG, None, true)?),
-        false => Err(failure::err_msg("Trading pair not available")),
-    }
+    });
+    Arc::clone(invites)
 }
 
-fn setup_s3_bucket() -> Bucket {
-    dotenv().ok();
-
-    let credentials: Credentials = Credentials::new(
-        Some(&env::var("AWS_ID").unwrap()),
-        Some(&env::var("AWS_SECRET").unwrap()),
-        None,
-        None,
-        None,
-    )
-    .unwrap();
-    let region: Region = env::var("S3_REGION").unwrap().parse().unwrap();
-
-    Bucket::new(&env::var("S3_BUCKET").unwrap(), region, credentials).unwrap()
+/// The commands this bot understands. `respond_to_message` is a plain `fn`,
+/// so the registry is built once and shared.
+fn registry() -> &'static CommandRegistry {
+    static REGISTRY: OnceLock<CommandRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let config = config();
+        let index = config.symbol_index.as_ref().and_then(|path| {
+            match SymbolIndex::load(Path::new(path)) {
+                Ok(index) => {
+                    println!("Loaded {} symbols from {}.", index.len(), path);
+                    Some(Arc::new(index))
+                }
+                Err(e) => {
+                    println!("Symbol index disabled, failed to load it: {}", e);
+                    None
+                }
+            }
+        });
+        let images = storage::open(&config.storage, config.retention.max_age, &store()).unwrap_or_else(|e| {
+            println!("Failed to set up chart image storage: {}", e);
+            std::process::exit(1);
+        });
+        retention::spawn(Arc::clone(&images), config.retention.clone());
+        let browsers = Arc::new(BrowserPool::new(config.browser.clone()));
+        browsers.spawn_health_checks();
+        if let Some(symbol) = config.prewarm_symbol.clone() {
+            let browsers = Arc::clone(&browsers);
+            thread::spawn(move || {
+                let request = ChartRequest::new(symbol, Interval::Days(1));
+                browsers.warm_up(|browser| screenshot::prepare_tab(browser, &request));
+                println!("Chart tabs warmed up.");
+            });
+        }
+        CommandRegistry::new()
+            .register(ChartCommand::new(config, index.clone(), browsers, images))
+            .register(SearchCommand::new(index))
+            .register(OutboxCommand::new(outbox(), config.admins.clone()))
+            .register(ApproveCommand::new(invites(), config.admins.clone()))
+    })
 }
 
-fn respond_to_message(authored_message: bot::AuthoredMessage) -> Option<bot::Message> {
-    dotenv().ok();
-    let now = Instant::now(); // initiate timer
-    let width: u16 = "1024".parse().unwrap();
-    let height: u16 = "800".parse().unwrap();
-    let bucket_name: &String = &env::var("S3_BUCKET").unwrap();
-    let region: &String = &env::var("S3_REGION").unwrap();
+fn respond_to_message(chat: &ShipChat, authored_message: bot::AuthoredMessage) -> Option<bot::Message> {
     println!("Received message: {}", authored_message.contents.to_formatted_string());
 
     let words = authored_message.contents.to_formatted_words();
-
-    // Error check to ensure sufficient number of words to check for command
-    if words.len() <= 2 {
-        println!("Error: invalid command");
-        if words[0] == "c" {
-            return Some(bot::Message::new().add_text(
-                "Unknown command.\n
-                Type `c <trading_pair> <timeframe>` to get the corresponding chart.\n
-                You can look up any trading pair and timeframe supported by TradingView.\n
-                Example: `c ethusd 4h`",
-            ));
-        }
-
-        return None;
-    }
-
-    if words[0] == "c" {
-        println!("Parsing command.");
-        let timeframe: String = parse_timeframe(words[2].to_string());
-        let url: String = format!("https://www.tradingview.com/widgetembed/?symbol={}&interval={}&theme=dark&style=1&hidetoptoolbar=1&symboledit=1&saveimage=1&withdateranges=1", words[1], timeframe);
-        println!("Getting screenshot from {}", url);
-        let shot = screenshot_tab(&url, width, height);
-        println!("Got TradingView screenshot, uploading to S3.");
-        match shot {
-            Ok(n) => {
-                let bucket: Bucket = setup_s3_bucket();
-
-                let filename: String = format!(
-                    "{}_{}_{:?}.png",
-                    words[1],
-                    parse_timeframe(timeframe),
-                    chrono::offset::Utc::now()
-                );
-
-                let (_, _code) = bucket
-                    .put_object_with_content_type_blocking(filename.clone(), &n, "image/png")
-                    .unwrap();
-
-                let file_location: String = format!(
-                    "https://{}.s3.{}.amazonaws.com/{}",
-                    bucket_name, region, filename
-                );
-                println!("Uploaded to S3. Sending URL to chat. Took {} seconds to process command.", now.elapsed().as_secs());
-
-                return Some(bot::Message::new().add_url(file_location.as_str()));
-            }
-            Err(_err) => {
-                let message = format!("Trading pair `{:?}` not available.", words[1]);
-                return Some(bot::Message::new().add_text(&message));
-            }
-        }
-    }
- 
-    None
+    let context = CommandContext {
+        chat: chat.clone(),
+        author: format!("~{}", authored_message.author.trim_start_matches('~')),
+        group: store().chat(chat).and_then(|record| record.group),
+    };
+    registry().dispatch(&context, &words)
 }
 
 fn main() {
+    // Load the bot config up front so a bad config fails before connecting to the ship
+    registry();
+
     // Not used at this time, but ready to be used for Milestone 2
     let mut shipchats: Vec<ShipChat> = Vec::new();
     let shipchat_a = ShipChat {
@@ -370,5 +159,22 @@ fn main() {
     shipchats.push(shipchat_b);
     shipchats.push(shipchat_a);
 
-    bot::Chatbot::new_with_local_config(respond_to_message, shipchats).run();
+    let bot = bot::Chatbot::new_with_local_config(
+        respond_to_message,
+        shipchats,
+        bot::ChatbotConfig {
+            workers: config().workers.clone(),
+            reconnect: config().reconnect.clone(),
+            welcome: config().welcome.clone(),
+        },
+        outbox(),
+        store(),
+        invites(),
+    );
+    match bot {
+        Ok(mut bot) => {
+            bot.run();
+        }
+        Err(e) => println!("Failed to load the bot's state: {}", e),
+    }
 }
@@ -0,0 +1,425 @@
+use json::{object, JsonValue};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+
+use crate::bot::ShipChat;
+use urbit_http_api::Message;
+
+/// Most permanent failures kept for operators to look at.
+const MAX_FAILURES: usize = 100;
+
+/// Settings for retrying replies that could not be sent.
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    /// File pending and failed replies are kept in across restarts.
+    pub path: PathBuf,
+    /// Attempts at sending a reply before giving up on it.
+    pub max_attempts: u32,
+    /// Wait before the first retry. Doubles with every failed attempt.
+    pub retry_delay: Duration,
+    /// Longest wait between two attempts.
+    pub max_retry_delay: Duration,
+}
+
+/// A reply waiting to be sent, or one that was given up on.
+#[derive(Clone, Debug)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub chat: ShipChat,
+    pub message: Message,
+    /// Failed attempts at sending it so far.
+    pub attempts: u32,
+    /// Unix time the reply was queued.
+    pub queued_at: u64,
+    /// Unix time of the next attempt.
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+/// What the sender should do next.
+#[derive(Debug)]
+pub enum NextSend {
+    Send(OutboxEntry),
+    /// Nothing is due before this unix time.
+    WaitUntil(u64),
+    Empty,
+}
+
+/// The outbox file could not be read.
+#[derive(Debug)]
+pub struct OutboxError(String);
+
+impl fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+#[derive(Default)]
+struct OutboxState {
+    next_id: u64,
+    pending: Vec<OutboxEntry>,
+    failed: Vec<OutboxEntry>,
+    /// Bumped on every change, so saves finishing out of order are noticed.
+    version: u64,
+}
+
+/// Seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|n| n.as_secs())
+        .unwrap_or_default()
+}
+
+impl OutboxEntry {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "id": self.id,
+            "ship": self.chat.ship_name.clone(),
+            "chat": self.chat.chat_name.clone(),
+            "message": self.message.to_json(),
+            "attempts": self.attempts,
+            "queued_at": self.queued_at,
+            "next_attempt": self.next_attempt,
+            "last_error": self.last_error.clone(),
+        }
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Self, OutboxError> {
+        let invalid = || OutboxError(format!("invalid outbox entry: {}", json.dump()));
+        Ok(OutboxEntry {
+            id: json["id"].as_u64().ok_or_else(invalid)?,
+            chat: ShipChat {
+                ship_name: json["ship"].as_str().ok_or_else(invalid)?.to_string(),
+                chat_name: json["chat"].as_str().ok_or_else(invalid)?.to_string(),
+            },
+            message: Message::from_json(json["message"].members().cloned().collect()),
+            attempts: json["attempts"].as_u32().unwrap_or_default(),
+            queued_at: json["queued_at"].as_u64().unwrap_or_default(),
+            next_attempt: json["next_attempt"].as_u64().unwrap_or_default(),
+            last_error: json["last_error"].as_str().map(String::from),
+        })
+    }
+}
+
+impl OutboxState {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "next_id": self.next_id,
+            "pending": self.pending.iter().map(OutboxEntry::to_json).collect::<Vec<_>>(),
+            "failed": self.failed.iter().map(OutboxEntry::to_json).collect::<Vec<_>>(),
+        }
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Self, OutboxError> {
+        let entries = |key: &str| json[key].members().map(OutboxEntry::from_json).collect::<Result<Vec<_>, _>>();
+        Ok(OutboxState {
+            next_id: json["next_id"].as_u64().unwrap_or_default(),
+            pending: entries("pending")?,
+            failed: entries("failed")?,
+            version: 0,
+        })
+    }
+}
+
+/// Replies waiting to be posted. Every change is written to disk, so replies
+/// survive a restart, and failed sends are retried with a growing delay
+/// until `max_attempts` is reached.
+pub struct Outbox {
+    config: OutboxConfig,
+    state: Mutex<OutboxState>,
+    changed: Notify,
+    /// Version of the state last written to disk.
+    saved: Arc<Mutex<u64>>,
+}
+
+impl Outbox {
+    /// An empty outbox that saves to `config.path`.
+    pub fn new(config: OutboxConfig) -> Self {
+        Outbox {
+            config,
+            state: Mutex::new(OutboxState::default()),
+            changed: Notify::new(),
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Load the replies left over from the last run, if any.
+    pub fn load(config: OutboxConfig) -> Result<Self, OutboxError> {
+        let outbox = Self::new(config);
+        if outbox.config.path.exists() {
+            let contents = fs::read_to_string(&outbox.config.path).map_err(|e| OutboxError(e.to_string()))?;
+            let json = json::parse(&contents).map_err(|e| OutboxError(e.to_string()))?;
+            *outbox.state.lock().unwrap() = OutboxState::from_json(&json)?;
+        }
+        Ok(outbox)
+    }
+
+    /// Queue `message` to be posted in `chat`.
+    pub fn push(&self, chat: ShipChat, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+        state.next_id += 1;
+        let entry = OutboxEntry {
+            id: state.next_id,
+            chat,
+            message,
+            attempts: 0,
+            queued_at: now,
+            next_attempt: now,
+            last_error: None,
+        };
+        state.pending.push(entry);
+        self.save(&mut state);
+        self.changed.notify_one();
+    }
+
+    /// The oldest reply that is due, skipping chats whose earlier replies are
+    /// still waiting for a retry so each chat gets its replies in order.
+    pub fn next(&self, now: u64) -> NextSend {
+        let state = self.state.lock().unwrap();
+        let mut seen = HashSet::new();
+        let mut wait_until = None;
+        for entry in &state.pending {
+            if !seen.insert(&entry.chat) {
+                continue;
+            }
+            if entry.next_attempt <= now {
+                return NextSend::Send(entry.clone());
+            }
+            wait_until = Some(wait_until.map_or(entry.next_attempt, |time: u64| time.min(entry.next_attempt)));
+        }
+        wait_until.map_or(NextSend::Empty, NextSend::WaitUntil)
+    }
+
+    /// Wait until a reply is queued or retried.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Remove a reply that was posted.
+    pub fn sent(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.retain(|entry| entry.id != id);
+        self.save(&mut state);
+    }
+
+    /// Record a failed attempt at sending a reply, scheduling a retry or
+    /// giving up on it once it has used all of its attempts.
+    pub fn failed_attempt(&self, id: u64, error: &str, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        let index = match state.pending.iter().position(|entry| entry.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+        let entry = &mut state.pending[index];
+        entry.attempts += 1;
+        entry.last_error = Some(error.to_string());
+        if entry.attempts >= self.config.max_attempts {
+            let entry = state.pending.remove(index);
+            println!(
+                "Giving up on reply to {} after {} attempts: {}",
+                entry.chat.key(),
+                entry.attempts,
+                error
+            );
+            state.failed.push(entry);
+            let excess = state.failed.len().saturating_sub(MAX_FAILURES);
+            state.failed.drain(..excess);
+        } else {
+            let delay = self
+                .config
+                .retry_delay
+                .checked_mul(1 << (entry.attempts - 1).min(16))
+                .map_or(self.config.max_retry_delay, |delay| delay.min(self.config.max_retry_delay));
+            entry.next_attempt = now + delay.as_secs();
+            println!(
+                "Failed to send reply to {} (attempt {}), retrying in {}s: {}",
+                entry.chat.key(),
+                entry.attempts,
+                delay.as_secs(),
+                error
+            );
+        }
+        self.save(&mut state);
+    }
+
+    /// Drop the replies waiting to be posted in `chat`, returning how many there were.
+    pub fn cancel(&self, chat: &ShipChat) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.pending.len();
+        state.pending.retain(|entry| entry.chat != *chat);
+        let cancelled = before - state.pending.len();
+        if cancelled > 0 {
+            self.save(&mut state);
+        }
+        cancelled
+    }
+
+    /// Number of replies waiting to be sent.
+    pub fn pending_count(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    /// Replies that were given up on, oldest first.
+    pub fn failures(&self) -> Vec<OutboxEntry> {
+        self.state.lock().unwrap().failed.clone()
+    }
+
+    /// Queue every failed reply again, returning how many there were. They
+    /// go ahead of newer replies to the same chats, so each chat still gets
+    /// its replies in order.
+    pub fn retry_failed(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+        let mut failed = std::mem::take(&mut state.failed);
+        for entry in &mut failed {
+            entry.attempts = 0;
+            entry.next_attempt = now;
+        }
+        let count = failed.len();
+        state.pending.extend(failed);
+        // Ids follow the order replies were queued in
+        state.pending.sort_by_key(|entry| entry.id);
+        self.save(&mut state);
+        self.changed.notify_one();
+        count
+    }
+
+    /// Write any change not saved yet to disk. The runtime drops saves that
+    /// have not started when it shuts down, so this is called after it stops.
+    pub fn flush(&self) {
+        let state = self.state.lock().unwrap();
+        if *self.saved.lock().unwrap() < state.version {
+            write_state(&self.config.path, &self.saved, state.version, state.to_json().pretty(2));
+        }
+    }
+
+    /// Write `state` to disk. On the tokio runtime the file is written on a
+    /// blocking thread, so sending and receiving are not held up.
+    fn save(&self, state: &mut OutboxState) {
+        state.version += 1;
+        let (version, contents) = (state.version, state.to_json().pretty(2));
+        let (path, saved) = (self.config.path.clone(), Arc::clone(&self.saved));
+        let write = move || write_state(&path, &saved, version, contents);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Write `contents`, the outbox at `version`, to `path` unless a later
+/// version was already written.
+fn write_state(path: &Path, saved: &Mutex<u64>, version: u64, contents: String) {
+    let mut saved = saved.lock().unwrap();
+    if *saved >= version {
+        return;
+    }
+    // Write to a temporary file first so a crash never leaves a half written outbox
+    let temp = path.with_extension("tmp");
+    match fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => *saved = version,
+        Err(e) => println!("Failed to save the outbox to {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{chat, TempDir};
+
+    fn config(dir: &TempDir) -> OutboxConfig {
+        OutboxConfig {
+            path: dir.path().join("outbox.json"),
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(10),
+            max_retry_delay: Duration::from_secs(15),
+        }
+    }
+
+    fn next_id(outbox: &Outbox, now: u64) -> Option<u64> {
+        match outbox.next(now) {
+            NextSend::Send(entry) => Some(entry.id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_retries_keep_chat_order() {
+        let dir = TempDir::new("outbox");
+        let outbox = Outbox::new(config(&dir));
+        outbox.push(chat("a"), Message::new().add_text("1"));
+        outbox.push(chat("a"), Message::new().add_text("2"));
+        outbox.push(chat("b"), Message::new().add_text("3"));
+        let now = unix_now();
+
+        outbox.failed_attempt(1, "offline", now);
+        // The retry of 1 holds up 2, but not the reply in the other chat
+        assert_eq!(next_id(&outbox, now), Some(3));
+        outbox.sent(3);
+        assert!(matches!(outbox.next(now), NextSend::WaitUntil(time) if time == now + 10));
+        assert_eq!(next_id(&outbox, now + 10), Some(1));
+
+        // The delay doubles but is capped, and the reply is dropped after the last attempt
+        outbox.failed_attempt(1, "offline", now);
+        assert!(matches!(outbox.next(now), NextSend::WaitUntil(time) if time == now + 15));
+        outbox.failed_attempt(1, "still offline", now);
+        assert_eq!(next_id(&outbox, now), Some(2));
+        let failures = outbox.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].last_error.as_deref(), Some("still offline"));
+
+        assert_eq!(outbox.retry_failed(), 1);
+        assert_eq!(outbox.pending_count(), 2);
+        // The retried reply goes out before the newer one
+        assert_eq!(next_id(&outbox, now), Some(1));
+        assert_eq!(outbox.cancel(&chat("a")), 2);
+        assert!(matches!(outbox.next(now), NextSend::Empty));
+    }
+
+    #[test]
+    fn test_pending_replies_survive_a_restart() {
+        let dir = TempDir::new("outbox");
+        let outbox = Outbox::new(config(&dir));
+        outbox.push(chat("a"), Message::new().add_text("hello"));
+        outbox.push(chat("a"), Message::new().add_text("sent"));
+        outbox.sent(2);
+
+        let reloaded = Outbox::load(config(&dir)).unwrap();
+        match reloaded.next(unix_now()) {
+            NextSend::Send(entry) => {
+                assert_eq!(entry.chat, chat("a"));
+                assert_eq!(entry.message.to_formatted_string().trim(), "hello");
+            }
+            other => panic!("expected a reply to send, got {:?}", other),
+        }
+        // New replies do not reuse ids
+        reloaded.sent(1);
+        reloaded.push(chat("a"), Message::new().add_text("again"));
+        assert_eq!(next_id(&reloaded, unix_now()), Some(3));
+    }
+
+    #[test]
+    fn test_saves_on_the_runtime_are_kept_in_order() {
+        let dir = TempDir::new("outbox");
+        let outbox = Outbox::new(config(&dir));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            for i in 0..20 {
+                outbox.push(chat("a"), Message::new().add_text(&i.to_string()));
+            }
+        });
+        drop(runtime);
+        outbox.flush();
+        assert_eq!(Outbox::load(config(&dir)).unwrap().pending_count(), 20);
+    }
+}
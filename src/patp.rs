@@ -0,0 +1,96 @@
+//! Ship names (`@p`) and the rank of the ship behind them, which follows
+//! from the number of syllables: one for a galaxy, two for a star, four for
+//! a planet and eight for a moon. Comets are written as two halves joined by
+//! `--`, as in `~dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod`.
+
+const PREFIXES: &str = "dozmarbinwansamlitsighidfidlissogdirwacsabwissibrigsoldopmodfoglidhopdardorlorhodfolrintogsilmirholpaslacrovlivdalsatlibtabhanticpidtorbolfosdotlosdilforpilramtirwintadbicdifrocwidbisdasmidloprilnardapmolsanlocnovsitnidtipsicropwitnatpanminritpodmottamtolsavposnapnopsomfinfonbanmorworsipronnorbotwicsocwatdolmagpicdavbidbaltimtasmalligsivtagpadsaldivdactansidfabtarmonranniswolmispallasdismaprabtobrollatlonnodnavfignomnibpagsopralbilhaddocridmocpacravripfaltodtiltinhapmicfanpattaclabmogsimsonpinlomrictapfirhasbosbatpochactidhavsaplindibhosdabbitbarracparloddosbortochilmactomdigfilfasmithobharmighinradmashalraglagfadtopmophabnilnosmilfopfamdatnoldinhatnacrisfotribhocnimlarfitwalrapsarnalmoslandondanladdovrivbacpollaptalpitnambonrostonfodponsovnocsorlavmatmipfip";
+const SUFFIXES: &str = "zodnecbudwessevpersutletfulpensytdurwepserwylsunrypsyxdyrnuphebpeglupdepdysputlughecryttyvsydnexlunmeplutseppesdelsulpedtemledtulmetwenbynhexfebpyldulhetmevruttylwydtepbesdexsefwycburderneppurrysrebdennutsubpetrulsynregtydsupsemwynrecmegnetsecmulnymtevwebsummutnyxrextebfushepbenmuswyxsymselrucdecwexsyrwetdylmynmesdetbetbeltuxtugmyrpelsyptermebsetdutdegtexsurfeltudnuxruxrenwytnubmedlytdusnebrumtynseglyxpunresredfunrevrefmectedrusbexlebduxrynnumpyxrygryxfeptyrtustyclegnemfermertenlusnussyltecmexpubrymtucfyllepdebbermughuttunbylsudpemdevlurdefbusbeprunmelpexdytbyttyplevmylwedducfurfexnulluclennerlexrupnedlecrydlydfenwelnydhusrelrudneshesfetdesretdunlernyrsebhulrylludremlysfynwerrycsugnysnyllyndyndemluxfedsedbecmunlyrtesmudnytbyrsenwegfyrmurtelreptegpecnelnevfes";
+
+/// The kind of a ship, from its place in the address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rank {
+    Galaxy,
+    Star,
+    Planet,
+    Moon,
+    Comet,
+}
+
+impl Rank {
+    pub fn parse(rank: &str) -> Option<Self> {
+        match rank {
+            "galaxy" => Some(Rank::Galaxy),
+            "star" => Some(Rank::Star),
+            "planet" => Some(Rank::Planet),
+            "moon" => Some(Rank::Moon),
+            "comet" => Some(Rank::Comet),
+            _ => None,
+        }
+    }
+}
+
+fn is_syllable(list: &str, word: &str) -> bool {
+    (0..256).any(|i| &list[i * 3..i * 3 + 3] == word)
+}
+
+/// Whether `words` are all two syllables long, as every word of a name
+/// longer than a galaxy's is.
+fn are_words(words: &[&str]) -> bool {
+    words.iter().all(|word| {
+        word.len() == 6 && word.is_ascii() && is_syllable(PREFIXES, &word[..3]) && is_syllable(SUFFIXES, &word[3..])
+    })
+}
+
+/// The rank of a ship name such as `~sampel-palnet`, with or without the
+/// `~`, or None if it is not a valid name.
+pub fn rank(name: &str) -> Option<Rank> {
+    let name = name.trim().trim_start_matches('~').to_lowercase();
+    if let Some((high, low)) = name.split_once("--") {
+        // Leading zero words of the high half are left out
+        let high: Vec<&str> = high.split('-').collect();
+        let low: Vec<&str> = low.split('-').collect();
+        let valid = (1..=4).contains(&high.len()) && low.len() == 4 && are_words(&high) && are_words(&low);
+        return if valid { Some(Rank::Comet) } else { None };
+    }
+    let words: Vec<&str> = name.split('-').collect();
+    match words.as_slice() {
+        [galaxy] if galaxy.len() == 3 => is_syllable(SUFFIXES, galaxy).then_some(Rank::Galaxy),
+        _ if !are_words(&words) => None,
+        [_] => Some(Rank::Star),
+        [_, _] => Some(Rank::Planet),
+        [_, _, _, _] => Some(Rank::Moon),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranks() {
+        let known = [
+            ("zod", Rank::Galaxy),
+            ("marzod", Rank::Star),
+            ("sampel-palnet", Rank::Planet),
+            ("dapnep-ronmyl", Rank::Planet),
+            ("ridlur-figbud-sampel-palnet", Rank::Moon),
+            ("dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful-marzod", Rank::Comet),
+            ("ribpyl--mosnyx-bisdem-nidful-marzod", Rank::Comet),
+        ];
+        for (name, expected) in known.iter() {
+            assert_eq!(rank(&format!("~{}", name)), Some(*expected));
+            assert_eq!(rank(name), Some(*expected));
+        }
+        assert_eq!(rank("~Sampel-Palnet"), Some(Rank::Planet));
+        assert_eq!(rank("~sampel-palxyz"), None);
+        assert_eq!(rank("~zo"), None);
+        assert_eq!(rank("~zod-marzod"), None);
+        assert_eq!(rank("~sampel-palnet-marzod"), None);
+        // Comets are only written with their halves apart
+        assert_eq!(rank("~dasres-ragnep-lislyt-ribpyl-mosnyx-bisdem-nidful-marzod"), None);
+        assert_eq!(rank("~sampel--palnet"), None);
+        assert_eq!(rank("~dasres-ragnep-lislyt-ribpyl--mosnyx-bisdem-nidful"), None);
+        assert_eq!(rank(""), None);
+    }
+}
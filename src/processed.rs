@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::bot::ShipChat;
+
+/// How long handled messages are remembered. Messages sent before the bot
+/// started are ignored anyway, so this only needs to cover clock drift
+/// between the bot and the ship.
+const REMEMBER_FOR: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often handled messages old enough to forget are looked for.
+const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+/// How far the ship's clock may be behind the bot's. Messages are stamped by
+/// the ship, so ones sent just after the bot started may look older.
+const CLOCK_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// Remembers which chat messages the bot has already handled, so messages
+/// that graph-store replays after a reconnect or restart are answered only
+/// once. Kept in a tree of the state database, keyed by chat and node index.
+///
+/// A message counts as handled once its reply is in the outbox, so one the
+/// bot was still working on when it stopped is answered after a restart.
+/// Messages that need no reply are not recorded, as replays of them are
+/// ignored again anyway.
+pub struct ProcessedMessages {
+    tree: sled::Tree,
+    /// Unix time in milliseconds the bot started, less `CLOCK_TOLERANCE`.
+    /// Messages sent earlier are never answered.
+    started_at: u64,
+    /// Messages being worked on, which replays must not start again.
+    claimed: Mutex<HashSet<String>>,
+    /// Unix time in milliseconds old messages were last forgotten.
+    pruned_at: Mutex<u64>,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|n| n.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl ProcessedMessages {
+    /// Use `tree` to record handled messages, forgetting ones old enough
+    /// that they can no longer come up.
+    pub fn new(tree: sled::Tree) -> Self {
+        let now = unix_millis();
+        let processed = ProcessedMessages {
+            tree,
+            started_at: now.saturating_sub(CLOCK_TOLERANCE.as_millis() as u64),
+            claimed: Mutex::new(HashSet::new()),
+            pruned_at: Mutex::new(now),
+        };
+        processed.prune(now);
+        processed
+    }
+
+    /// Forget the messages handled longer than `REMEMBER_FOR` before `now`.
+    fn prune(&self, now: u64) {
+        let cutoff = now.saturating_sub(REMEMBER_FOR.as_millis() as u64);
+        let expired = self.tree.iter().filter_map(Result::ok).filter(|(_, handled_at)| {
+            let handled_at: [u8; 8] = handled_at.as_ref().try_into().unwrap_or_default();
+            u64::from_be_bytes(handled_at) < cutoff
+        });
+        for (id, _) in expired {
+            self.tree.remove(id).ok();
+        }
+    }
+
+    /// Start working on the message at `index` in `chat`. Returns false if it
+    /// was handled or claimed before, or was sent before the bot started.
+    pub fn claim(&self, chat: &ShipChat, index: &str, time_sent: u64) -> bool {
+        if time_sent < self.started_at {
+            return false;
+        }
+        let id = format!("{}{}", chat.key(), index);
+        // Answering twice beats never answering
+        let handled = self.tree.contains_key(&id).unwrap_or_else(|e| {
+            println!("Failed to look up handled message: {}", e);
+            false
+        });
+        !handled && self.claimed.lock().unwrap().insert(id)
+    }
+
+    /// Record the claimed message at `index` in `chat` as handled, once its
+    /// reply is queued. Old messages are forgotten every `PRUNE_EVERY`.
+    pub fn handled(&self, chat: &ShipChat, index: &str) {
+        let id = format!("{}{}", chat.key(), index);
+        let now = unix_millis();
+        if let Err(e) = self.tree.insert(&id, &now.to_be_bytes()[..]) {
+            println!("Failed to record handled message: {}", e);
+        }
+        self.claimed.lock().unwrap().remove(&id);
+
+        let mut pruned_at = self.pruned_at.lock().unwrap();
+        if now.saturating_sub(*pruned_at) >= PRUNE_EVERY.as_millis() as u64 {
+            *pruned_at = now;
+            self.prune(now);
+        }
+    }
+
+    /// Let go of the claimed message at `index` in `chat`, which turned out
+    /// to need no reply.
+    pub fn ignored(&self, chat: &ShipChat, index: &str) {
+        self.claimed.lock().unwrap().remove(&format!("{}{}", chat.key(), index));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat;
+    use crate::state_store::StateStore;
+
+    #[test]
+    fn test_each_message_is_handled_once() {
+        let store = StateStore::temporary();
+        let processed = ProcessedMessages::new(store.tree("processed_messages").unwrap());
+        let now = unix_millis();
+
+        assert!(processed.claim(&chat("a"), "/170141184505", now));
+        // A replay while the reply is on its way is not handled again
+        assert!(!processed.claim(&chat("a"), "/170141184505", now));
+        processed.handled(&chat("a"), "/170141184505");
+        assert!(!processed.claim(&chat("a"), "/170141184505", now));
+        // The same index in another chat is another message
+        assert!(processed.claim(&chat("b"), "/170141184505", now));
+        // Messages from before the bot started are ignored, allowing for the
+        // ship's clock being a little behind
+        assert!(processed.claim(&chat("a"), "/170141184506", now - 60 * 1000));
+        assert!(!processed.claim(&chat("a"), "/170141184507", processed.started_at - 1));
+
+        // A restarted bot still knows what was handled, but answers messages
+        // whose reply was never queued
+        let reloaded = ProcessedMessages::new(store.tree("processed_messages").unwrap());
+        assert!(!reloaded.claim(&chat("a"), "/170141184505", now));
+        assert!(reloaded.claim(&chat("b"), "/170141184505", now));
+    }
+
+    #[test]
+    fn test_only_replied_messages_are_kept() {
+        let store = StateStore::temporary();
+        let processed = ProcessedMessages::new(store.tree("processed_messages").unwrap());
+        let now = unix_millis();
+
+        assert!(processed.claim(&chat("a"), "/1", now));
+        processed.ignored(&chat("a"), "/1");
+        assert!(processed.tree.is_empty());
+        assert!(processed.claim(&chat("a"), "/1", now));
+
+        // Old messages are forgotten as new ones are handled
+        let old = now - REMEMBER_FOR.as_millis() as u64 - 1;
+        processed.tree.insert("~zod/old/1", &old.to_be_bytes()[..]).unwrap();
+        processed.handled(&chat("a"), "/2");
+        assert!(processed.tree.contains_key("~zod/old/1").unwrap());
+        *processed.pruned_at.lock().unwrap() = now - PRUNE_EVERY.as_millis() as u64;
+        processed.handled(&chat("a"), "/3");
+        assert!(!processed.tree.contains_key("~zod/old/1").unwrap());
+        assert_eq!(processed.tree.len(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::NaiveDate;
+use std::fmt;
+
+/// Format used for explicit dates, e.g. `2021-06-01`.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Longest relative range that can be requested, in months.
+const MAX_MONTHS: u32 = 100 * 12;
+
+/// How much history a chart should show.
+///
+/// Written after the interval as `last <n><unit>` (`last 90d`, `last 3y`),
+/// `from <date> [to <date>]`, `ytd` or `all`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChartRange {
+    /// The most recent number of days.
+    Days(u32),
+    /// The most recent number of months.
+    Months(u32),
+    /// Year to date.
+    YearToDate,
+    /// All available history.
+    All,
+    /// Between two dates, both inclusive.
+    Between(NaiveDate, NaiveDate),
+}
+
+/// A date range that could not be parsed or makes no sense.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RangeError(String);
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RangeError {}
+
+fn parse_date(input: &str) -> Result<NaiveDate, RangeError> {
+    NaiveDate::parse_from_str(input, DATE_FORMAT)
+        .map_err(|_| RangeError(format!("Invalid date `{}`, expected YYYY-MM-DD.", input)))
+}
+
+/// Parse the `3y` in `last 3y` into a range.
+fn parse_period(input: &str) -> Result<ChartRange, RangeError> {
+    let invalid = || {
+        RangeError(format!(
+            "Invalid period `{}`. Use a number followed by d, w, mo or y, e.g. `last 6mo`.",
+            input
+        ))
+    };
+    let digits_end = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(digits_end);
+    let count: u32 = match number {
+        "" => 1,
+        digits => digits.parse().map_err(|_| invalid())?,
+    };
+    let range = match unit.to_lowercase().as_str() {
+        "d" | "day" | "days" => ChartRange::Days(count),
+        "w" | "wk" | "week" | "weeks" => ChartRange::Days(count.saturating_mul(7)),
+        "mo" | "mth" | "month" | "months" => ChartRange::Months(count),
+        "y" | "yr" | "year" | "years" => ChartRange::Months(count.saturating_mul(12)),
+        _ => return Err(invalid()),
+    };
+    match range {
+        ChartRange::Days(0) | ChartRange::Months(0) => Err(invalid()),
+        ChartRange::Days(days) if days > MAX_MONTHS * 31 => Err(invalid()),
+        ChartRange::Months(months) if months > MAX_MONTHS => Err(invalid()),
+        range => Ok(range),
+    }
+}
+
+impl ChartRange {
+    /// Parse a range from the start of `words`. Returns `Ok(None)` if the
+    /// words do not start with a range keyword, otherwise the range and how
+    /// many words it used. `today` bounds explicit dates.
+    pub fn parse_words(
+        words: &[String],
+        today: NaiveDate,
+    ) -> Result<Option<(ChartRange, usize)>, RangeError> {
+        let keyword = match words.first() {
+            Some(word) => word.to_lowercase(),
+            None => return Ok(None),
+        };
+        match keyword.as_str() {
+            "all" | "max" => Ok(Some((ChartRange::All, 1))),
+            "ytd" => Ok(Some((ChartRange::YearToDate, 1))),
+            "last" => {
+                let period = words
+                    .get(1)
+                    .ok_or_else(|| RangeError("Expected a period after `last`, e.g. `last 3y`.".to_string()))?;
+                // Allow the number and unit to be separate words, as in `last 3 years`.
+                if let Some(unit) = words.get(2) {
+                    let is_number = period.chars().all(|c| c.is_ascii_digit());
+                    if is_number {
+                        if let Ok(range) = parse_period(&format!("{}{}", period, unit)) {
+                            return Ok(Some((range, 3)));
+                        }
+                    }
+                }
+                Ok(Some((parse_period(period)?, 2)))
+            }
+            "from" => {
+                let from = words
+                    .get(1)
+                    .ok_or_else(|| RangeError("Expected a date after `from`, e.g. `from 2021-01-01`.".to_string()))?;
+                let from = parse_date(from)?;
+                let (to, used) = match words.get(2).map(|word| word.to_lowercase()) {
+                    Some(word) if word == "to" => {
+                        let to = words
+                            .get(3)
+                            .ok_or_else(|| RangeError("Expected a date after `to`.".to_string()))?;
+                        (parse_date(to)?, 4)
+                    }
+                    _ => (today, 2),
+                };
+                if from >= to {
+                    return Err(RangeError(format!("The start date {} must be before the end date {}.", from, to)));
+                }
+                if to > today {
+                    return Err(RangeError(format!("The end date {} is in the future.", to)));
+                }
+                Ok(Some((ChartRange::Between(from, to), used)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The TradingView widget parameters that select this range.
+    pub fn url_params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ChartRange::Days(days) => vec![("range", format!("{}D", days))],
+            ChartRange::Months(months) => vec![("range", format!("{}M", months))],
+            ChartRange::YearToDate => vec![("range", "YTD".to_string())],
+            ChartRange::All => vec![("range", "ALL".to_string())],
+            ChartRange::Between(from, to) => vec![
+                ("from", from.and_hms(0, 0, 0).timestamp().to_string()),
+                ("to", to.and_hms(23, 59, 59).timestamp().to_string()),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2021, 12, 31)
+    }
+
+    fn parse(text: &str) -> Result<Option<(ChartRange, usize)>, RangeError> {
+        ChartRange::parse_words(&words(text), today())
+    }
+
+    #[test]
+    fn test_relative_ranges() {
+        assert_eq!(parse("last 3y"), Ok(Some((ChartRange::Months(36), 2))));
+        assert_eq!(parse("last 3 years rsi"), Ok(Some((ChartRange::Months(36), 3))));
+        assert_eq!(parse("last 2w"), Ok(Some((ChartRange::Days(14), 2))));
+        assert_eq!(parse("ytd"), Ok(Some((ChartRange::YearToDate, 1))));
+        assert_eq!(parse("all"), Ok(Some((ChartRange::All, 1))));
+        assert!(parse("last 0d").is_err());
+        assert!(parse("last 3x").is_err());
+    }
+
+    #[test]
+    fn test_date_ranges() {
+        let from = NaiveDate::from_ymd(2021, 1, 1);
+        let to = NaiveDate::from_ymd(2021, 6, 1);
+        assert_eq!(
+            parse("from 2021-01-01 to 2021-06-01"),
+            Ok(Some((ChartRange::Between(from, to), 4)))
+        );
+        assert_eq!(parse("from 2021-01-01"), Ok(Some((ChartRange::Between(from, today()), 2))));
+        assert!(parse("from 2021-06-01 to 2021-01-01").is_err());
+        assert!(parse("from 2021-01-01 to 2030-01-01").is_err());
+        assert!(parse("from yesterday").is_err());
+    }
+
+    #[test]
+    fn test_not_a_range() {
+        assert_eq!(parse("rsi"), Ok(None));
+    }
+}
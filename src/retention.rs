@@ -0,0 +1,149 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::outbox::unix_now;
+use crate::storage::{ImageStore, StorageError, CHART_PREFIX};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// How long chart images are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionConfig {
+    /// Age after which images are deleted, or None to keep them forever.
+    pub max_age: Option<Duration>,
+    /// How often old images are looked for.
+    pub interval: Duration,
+    /// Only report what would be deleted.
+    pub dry_run: bool,
+    /// Leave deleting to a lifecycle rule installed on the S3 bucket.
+    pub lifecycle_rule: bool,
+}
+
+/// What a clean up deleted, or would have in a dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub deleted: usize,
+    /// Bytes freed by the deleted images.
+    pub freed: u64,
+    /// Images that could not be deleted.
+    pub failed: usize,
+    pub dry_run: bool,
+}
+
+/// `bytes` written as e.g. `1.5 MB`.
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "Would delete" } else { "Deleted" };
+        write!(f, "{} {} old chart images, freeing {}", verb, self.deleted, human_size(self.freed))?;
+        if self.failed > 0 {
+            write!(f, " ({} could not be deleted)", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delete the chart images last written more than `max_age` before `now`,
+/// a unix time.
+pub fn clean_up(images: &dyn ImageStore, max_age: Duration, now: u64, dry_run: bool) -> Result<Report, StorageError> {
+    let cutoff = now.saturating_sub(max_age.as_secs());
+    let mut report = Report {
+        dry_run,
+        ..Report::default()
+    };
+    for image in images.list(CHART_PREFIX)?.into_iter().filter(|image| image.modified < cutoff) {
+        if dry_run {
+            println!("Would delete {}", image.key);
+        } else if let Err(e) = images.delete(&image.key) {
+            println!("Failed to delete {}: {}", image.key, e);
+            report.failed += 1;
+            continue;
+        }
+        report.deleted += 1;
+        report.freed += image.size;
+    }
+    Ok(report)
+}
+
+/// Start deleting old chart images in the background, if there is a maximum age.
+pub fn spawn(images: Arc<dyn ImageStore>, config: RetentionConfig) {
+    let max_age = match config.max_age {
+        Some(max_age) => max_age,
+        None => return,
+    };
+    thread::spawn(move || {
+        if config.lifecycle_rule && !config.dry_run {
+            let days = max_age.as_secs().div_ceil(DAY_SECS).max(1) as u32;
+            match images.expire_after(CHART_PREFIX, days) {
+                Ok(()) => println!("Chart images now expire after {} days.", days),
+                Err(e) => println!("Failed to install the chart image lifecycle rule: {}", e),
+            }
+        }
+        loop {
+            // Uploads may disappear a little early: lifecycle rules run about once a day
+            let margin = if config.lifecycle_rule { DAY_SECS } else { 0 };
+            if !config.lifecycle_rule || config.dry_run {
+                match clean_up(images.as_ref(), max_age, unix_now(), config.dry_run) {
+                    Ok(report) => println!("{}.", report),
+                    Err(e) => println!("Failed to clean up old chart images: {}", e),
+                }
+            }
+            if !config.dry_run {
+                images.forget_uploads_before(unix_now().saturating_sub(max_age.as_secs() + margin));
+            }
+            thread::sleep(config.interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Access, LocalStore};
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_clean_up() {
+        let dir = TempDir::new("retention");
+        let images = LocalStore::new(dir.path().to_path_buf(), "http://localhost".to_string()).unwrap();
+        images.put_png("charts/2021/01/01/old.png", &[0; 2048], Access::Public).unwrap();
+        images.put_png("charts/2021/01/02/new.png", &[0; 10], Access::Public).unwrap();
+        images.put_png("other.png", &[0; 10], Access::Public).unwrap();
+        let listed = images.list(CHART_PREFIX).unwrap();
+        assert_eq!(listed.len(), 2);
+        let old = listed.iter().find(|image| image.key.ends_with("old.png")).unwrap();
+        assert_eq!(old.size, 2048);
+
+        // Two days from now, with `new.png` just written
+        let now = unix_now() + 2 * DAY_SECS;
+        let new = dir.path().join("charts/2021/01/02/new.png");
+        let touched = std::time::SystemTime::now() + Duration::from_secs(2 * DAY_SECS);
+        fs::File::options().write(true).open(&new).unwrap().set_modified(touched).unwrap();
+
+        let report = clean_up(&images, Duration::from_secs(DAY_SECS), now, true).unwrap();
+        assert_eq!(report.to_string(), "Would delete 1 old chart images, freeing 2.0 KB");
+        assert_eq!(images.list(CHART_PREFIX).unwrap().len(), 2);
+
+        let report = clean_up(&images, Duration::from_secs(DAY_SECS), now, false).unwrap();
+        assert_eq!((report.deleted, report.freed, report.failed), (1, 2048, 0));
+        let left: Vec<String> = images.list("").unwrap().into_iter().map(|image| image.key).collect();
+        assert_eq!(left.len(), 2);
+        assert!(!left.iter().any(|key| key.ends_with("old.png")));
+    }
+}
@@ -0,0 +1,90 @@
+use headless_chrome::{browser::tab::Tab, protocol::page::ScreenshotFormat};
+use serde_json::Value;
+
+use crate::browser_pool::{BrowserPool, PooledBrowser, PooledTab};
+use crate::chart_request::ChartRequest;
+
+/// Switches the loaded widget to another symbol and interval through
+/// TradingView's in-page API. Resolves to "ok" once the new data is drawn.
+const SWITCH_SYMBOL_JS: &str = r#"
+new Promise(function (resolve, reject) {
+    setTimeout(function () { reject("timed out"); }, 5000);
+    var chart = window.TradingViewApi.activeChart();
+    chart.setResolution(INTERVAL, function () {
+        chart.setSymbol(SYMBOL, function () { resolve("ok"); });
+    });
+})
+"#;
+
+/// Load `url` in `tab` and wait for the chart and its legend to render.
+fn load_chart(tab: &Tab, url: &str) -> Result<(), failure::Error> {
+    tab.navigate_to(url)?;
+    tab.wait_until_navigated()?;
+    tab.wait_for_element(".chart-gui-wrapper > canvas")?;
+    tab.wait_for_element("[data-name='legend-series-item']")?;
+    Ok(())
+}
+
+/// Switch the chart already loaded in `tab` to the request's symbol and
+/// interval without reloading the page.
+fn switch_chart(tab: &Tab, request: &ChartRequest) -> Result<(), failure::Error> {
+    // Both values are JSON encoded so they are plain string literals in the script
+    let script = SWITCH_SYMBOL_JS
+        .replace("INTERVAL", &Value::from(request.interval.to_tradingview()).to_string())
+        .replace("SYMBOL", &Value::from(request.symbol.to_string()).to_string());
+    let result = tab.evaluate(&script, true)?;
+    match result.value {
+        Some(Value::String(status)) if status == "ok" => Ok(()),
+        other => Err(failure::format_err!("in-page symbol switch failed: {:?}", other)),
+    }
+}
+
+/// Put the requested chart in `pooled`, switching in place when the tab
+/// already shows a chart with the same layout and loading the page otherwise.
+fn show_chart(pooled: &mut PooledTab, request: &ChartRequest) -> Result<(), failure::Error> {
+    let layout = request.layout_key();
+    if layout.is_some() && pooled.loaded_page == layout {
+        match switch_chart(&pooled.tab, request) {
+            Ok(()) => return Ok(()),
+            Err(e) => println!("Falling back to a full page load: {}", e),
+        }
+    }
+    pooled.loaded_page = None;
+    load_chart(&pooled.tab, request.to_url().as_str())?;
+    pooled.loaded_page = layout;
+    Ok(())
+}
+
+/// Load `request` in one tab of `browser` so later requests with the same
+/// layout and size can switch to their symbol in place.
+pub fn prepare_tab(browser: &mut PooledBrowser, request: &ChartRequest) -> Result<(), failure::Error> {
+    let (width, height) = request.style.size.dimensions();
+    show_chart(browser.tab(width, height)?, request)
+}
+
+/// Render `request` in a pooled headless Chrome tab and return a PNG
+/// screenshot of the chart.
+pub fn screenshot_tab(pool: &BrowserPool, request: &ChartRequest) -> Result<Vec<u8>, failure::Error> {
+    let (width, height) = request.style.size.dimensions();
+    let mut browser = pool.checkout()?;
+    let pooled = browser.tab(width, height)?;
+    show_chart(pooled, request)?;
+
+    let tab = &pooled.tab;
+    let legend = tab.wait_for_element("[data-name='legend-series-item']")?;
+    let is_available = legend
+        .call_js_fn(
+            r#"
+        function containsNA () {
+            return !this.innerText.includes("n/a");
+        }
+    "#,
+            false,
+        )?
+        .value;
+
+    match is_available.eq(&Some(Value::Bool(true))) {
+        true => Ok(tab.capture_screenshot(ScreenshotFormat::PNG, None, true)?),
+        false => Err(failure::err_msg("Trading pair not available")),
+    }
+}
@@ -0,0 +1,461 @@
+use json::{object, JsonValue};
+use reqwest::header::{HeaderValue, CONTENT_TYPE, COOKIE};
+use reqwest::Client;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+use urbit_http_api::ShipInterface;
+
+/// How long a request to the ship may take before it is given up on.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The ship sends a heartbeat every 20 seconds, so a stream that stays quiet
+/// for longer than this has been lost.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The event stream could not be opened or was lost.
+#[derive(Debug)]
+pub struct EventError(String);
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for EventError {}
+
+impl From<reqwest::Error> for EventError {
+    fn from(e: reqwest::Error) -> Self {
+        EventError(e.to_string())
+    }
+}
+
+/// Delay between attempts at reconnecting to the ship, doubling after every
+/// failed attempt.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Start over from the initial delay, e.g. once connected again.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// A single server-sent event.
+#[derive(Debug, PartialEq)]
+struct SseEvent {
+    id: Option<u64>,
+    data: String,
+}
+
+/// Splits a stream of bytes into server-sent events.
+#[derive(Default)]
+struct SseParser {
+    /// Bytes of the line being read. Kept undecoded, as a chunk may end
+    /// halfway through a character.
+    buffer: Vec<u8>,
+    id: Option<u64>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Add bytes read from the stream, returning the events they complete.
+    fn feed(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = vec![];
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(SseEvent {
+                        id: self.id.take(),
+                        data: self.data.join("\n"),
+                    });
+                    self.data.clear();
+                }
+                continue;
+            }
+            // Lines starting with `:` are comments, which the ship uses as heartbeats
+            let (field, value) = match line.find(':') {
+                Some(0) => continue,
+                Some(i) => (&line[..i], line[i + 1..].strip_prefix(' ').unwrap_or(&line[i + 1..])),
+                None => (line, ""),
+            };
+            match field {
+                "id" => self.id = value.parse().ok(),
+                "data" => self.data.push(value.to_string()),
+                _ => (),
+            }
+        }
+        events
+    }
+}
+
+/// Updates from one app and path on the ship.
+struct Subscription {
+    app: String,
+    path: String,
+    id: u64,
+    messages: VecDeque<String>,
+}
+
+/// An Eyre channel whose events are read asynchronously, so the bot can
+/// sleep until the ship has something for it instead of polling.
+pub struct EventChannel {
+    ship_name: String,
+    session_auth: HeaderValue,
+    client: Client,
+    url: String,
+    next_id: u64,
+    subscriptions: Vec<Subscription>,
+    /// Ids of subscriptions the ship ended, to be subscribed to again.
+    kicked: Vec<u64>,
+    /// Id of the latest event stored but not acknowledged yet.
+    unacked: Option<u64>,
+    events: UnboundedReceiver<Result<SseEvent, EventError>>,
+}
+
+impl EventChannel {
+    /// Open a new channel on `ship` and start reading its event stream.
+    pub async fn open(ship: &ShipInterface) -> Result<Self, EventError> {
+        let client = Client::builder().connect_timeout(REQUEST_TIMEOUT).build()?;
+        let uid = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|n| n.as_micros())
+            .unwrap_or_default();
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut channel = EventChannel {
+            ship_name: ship.ship_name.clone(),
+            session_auth: ship.session_auth.clone(),
+            client,
+            url: format!("{}/~/channel/{}", ship.url, uid),
+            next_id: 1,
+            subscriptions: vec![],
+            kicked: vec![],
+            unacked: None,
+            events,
+        };
+
+        // The channel only exists once something has been sent on it
+        let id = channel.take_id();
+        channel
+            .put(object! {
+                "id": id,
+                "action": "poke",
+                "ship": channel.ship_name.clone(),
+                "app": "hood",
+                "mark": "helm-hi",
+                "json": "Opening channel",
+            })
+            .await?;
+
+        let response = channel
+            .client
+            .get(&channel.url)
+            .header(COOKIE, channel.session_auth.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        tokio::spawn(Self::read_stream(response, sender));
+        Ok(channel)
+    }
+
+    /// Subscribe to updates from `app` on `path`.
+    pub async fn subscribe(&mut self, app: &str, path: &str) -> Result<(), EventError> {
+        let id = self.take_id();
+        self.put(object! {
+            "id": id,
+            "action": "subscribe",
+            "ship": self.ship_name.clone(),
+            "app": app,
+            "path": path,
+        })
+        .await?;
+        self.subscriptions.push(Subscription {
+            app: app.to_string(),
+            path: path.to_string(),
+            id,
+            messages: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    /// The URL of the channel, so it can be deleted once it is replaced.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Delete the channel at `url`, e.g. one left behind by a lost connection,
+    /// so channels do not pile up on the ship.
+    pub async fn delete_channel(&self, url: &str) -> Result<(), EventError> {
+        self.client
+            .delete(url)
+            .header(COOKIE, self.session_auth.clone())
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Take the oldest unhandled update from the subscription to `app` on `path`.
+    pub fn pop_message(&mut self, app: &str, path: &str) -> Option<String> {
+        self.subscriptions
+            .iter_mut()
+            .find(|sub| sub.app == app && sub.path == path)?
+            .messages
+            .pop_front()
+    }
+
+    /// Wait until the ship sends something, then move every event that has
+    /// arrived into its subscription. Returns an error once the stream is lost.
+    ///
+    /// Safe to cancel: nothing is awaited once events are taken from the
+    /// stream. Call `sync` afterwards to acknowledge them.
+    pub async fn wait_for_events(&mut self) -> Result<(), EventError> {
+        let first = self
+            .events
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(EventError("event stream closed".to_string())))?;
+        if let Some(id) = self.store(first) {
+            self.unacked = Some(id);
+        }
+        while let Ok(event) = self.events.try_recv() {
+            if let Some(id) = self.store(event?) {
+                self.unacked = Some(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledge the events stored so far and subscribe again to the
+    /// subscriptions the ship ended. Work that is cut short is picked up by
+    /// the next call.
+    pub async fn sync(&mut self) -> Result<(), EventError> {
+        // Acknowledging the latest event tells the ship it can drop everything before it
+        if let Some(event_id) = self.unacked {
+            let id = self.take_id();
+            self.put(object! { "id": id, "action": "ack", "event-id": event_id }).await?;
+            self.unacked = None;
+        }
+        self.resubscribe_kicked().await
+    }
+
+    /// Subscribe again to the subscriptions the ship ended, which Gall does
+    /// e.g. when an agent is reloaded after an OTA update. Each one is only
+    /// dropped from `kicked` once the ship has the new subscription.
+    async fn resubscribe_kicked(&mut self) -> Result<(), EventError> {
+        while let Some(&kicked) = self.kicked.first() {
+            let id = self.take_id();
+            let (app, path) = match self.subscriptions.iter().find(|sub| sub.id == kicked) {
+                Some(sub) => (sub.app.clone(), sub.path.clone()),
+                None => {
+                    self.kicked.remove(0);
+                    continue;
+                }
+            };
+            println!("The ship ended the subscription to {}{}, subscribing again.", app, path);
+            let action = object! {
+                "id": id,
+                "action": "subscribe",
+                "ship": self.ship_name.clone(),
+                "app": app,
+                "path": path,
+            };
+            self.put(action).await?;
+            if let Some(sub) = self.subscriptions.iter_mut().find(|sub| sub.id == kicked) {
+                sub.id = id;
+            }
+            self.kicked.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Store an event in the subscription it belongs to, returning its id.
+    fn store(&mut self, event: SseEvent) -> Option<u64> {
+        let json = json::parse(&event.data).ok()?;
+        let sub_id = json["id"].as_u64();
+        if let Some(sub) = self.subscriptions.iter_mut().find(|sub| Some(sub.id) == sub_id) {
+            if json["response"] == "quit" {
+                self.kicked.push(sub.id);
+            } else if !json["json"].is_null() {
+                sub.messages.push_back(json["json"].dump());
+            }
+        }
+        event.id
+    }
+
+    async fn put(&self, action: JsonValue) -> Result<(), EventError> {
+        let body = JsonValue::Array(vec![action]).dump();
+        self.client
+            .put(&self.url)
+            .header(COOKIE, self.session_auth.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Forward events from the stream until it ends, goes quiet or the
+    /// channel is dropped.
+    async fn read_stream(mut response: reqwest::Response, events: UnboundedSender<Result<SseEvent, EventError>>) {
+        let mut parser = SseParser::default();
+        let error = loop {
+            let chunk = match timeout(IDLE_TIMEOUT, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break EventError("the ship closed the event stream".to_string()),
+                Ok(Err(e)) => break e.into(),
+                Err(_) => break EventError("no heartbeat from the ship".to_string()),
+            };
+            for event in parser.feed(&chunk) {
+                if events.send(Ok(event)).is_err() {
+                    return;
+                }
+            }
+        };
+        events.send(Err(error)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    type EventSender = UnboundedSender<Result<SseEvent, EventError>>;
+
+    /// A channel at `url` subscribed to graph-store and group-store, with the
+    /// sender its events are read from.
+    fn channel(url: &str) -> (EventChannel, EventSender) {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut channel = EventChannel {
+            ship_name: "zod".to_string(),
+            session_auth: HeaderValue::from_static("urbauth=0"),
+            client: Client::new(),
+            url: url.to_string(),
+            next_id: 3,
+            subscriptions: vec![],
+            kicked: vec![],
+            unacked: None,
+            events,
+        };
+        for (id, app) in [(1, "graph-store"), (2, "group-store")] {
+            channel.subscriptions.push(Subscription {
+                app: app.to_string(),
+                path: "/updates".to_string(),
+                id,
+                messages: VecDeque::new(),
+            });
+        }
+        (channel, sender)
+    }
+
+    fn event(id: u64, data: &str) -> SseEvent {
+        SseEvent {
+            id: Some(id),
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_quit_subscriptions_are_marked() {
+        let (mut channel, _sender) = channel("http://localhost/~/channel/1");
+        assert_eq!(channel.store(event(7, r#"{"id": 2, "response": "diff", "json": {"a": 1}}"#)), Some(7));
+        assert_eq!(channel.store(event(8, r#"{"id": 1, "response": "quit"}"#)), Some(8));
+        assert_eq!(channel.kicked, vec![1]);
+        assert_eq!(channel.pop_message("group-store", "/updates"), Some(r#"{"a":1}"#.to_string()));
+        assert_eq!(channel.pop_message("graph-store", "/updates"), None);
+    }
+
+    #[tokio::test]
+    async fn test_events_survive_a_cancelled_ack() {
+        // A ship that never answers, so acknowledging hangs
+        let ship = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut channel, sender) = channel(&format!("http://{}/~/channel/1", ship.local_addr().unwrap()));
+        sender.send(Ok(event(5, r#"{"id": 1, "response": "diff", "json": {"a": 1}}"#))).unwrap();
+        sender.send(Ok(event(6, r#"{"id": 2, "response": "quit"}"#))).unwrap();
+
+        let cancelled = timeout(Duration::from_millis(200), async {
+            channel.wait_for_events().await?;
+            channel.sync().await
+        });
+        assert!(cancelled.await.is_err());
+        // The events can still be handled, and the ack and resubscription are retried
+        assert_eq!(channel.pop_message("graph-store", "/updates"), Some(r#"{"a":1}"#.to_string()));
+        assert_eq!(channel.unacked, Some(6));
+        assert_eq!(channel.kicked, vec![2]);
+
+        // So is a resubscription cut short
+        channel.unacked = None;
+        assert!(timeout(Duration::from_millis(200), channel.sync()).await.is_err());
+        assert_eq!(channel.kicked, vec![2]);
+        assert_eq!(channel.subscriptions[1].id, 2);
+    }
+
+    #[test]
+    fn test_parse_events_across_chunks() {
+        let mut parser = SseParser::default();
+        assert!(parser.feed(b"id: 3\ndata: {\"id\":").is_empty());
+        let events = parser.feed(b" 2}\n\n:\n\nid: 4\r\ndata: a\r\ndata: b\r\n\r\n");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    id: Some(3),
+                    data: "{\"id\": 2}".to_string()
+                },
+                SseEvent {
+                    id: Some(4),
+                    data: "a\nb".to_string()
+                },
+            ]
+        );
+
+        // A character split between two chunks
+        let text = "data: gm ☀️\n\n".as_bytes();
+        assert!(parser.feed(&text[..10]).is_empty());
+        assert_eq!(parser.feed(&text[10..])[0].data, "gm ☀️");
+    }
+}
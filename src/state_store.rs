@@ -0,0 +1,241 @@
+use json::{object, JsonValue};
+use std::fmt;
+use std::path::Path;
+
+use crate::bot::ShipChat;
+use crate::outbox::unix_now;
+
+/// The state database could not be opened or written.
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sled::Error> for StoreError {
+    fn from(e: sled::Error) -> Self {
+        StoreError(e.to_string())
+    }
+}
+
+/// Where the bot stands with a chat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Membership {
+    /// A join was sent but has not been confirmed yet.
+    Joining,
+    Joined,
+    /// The last join failed, so it is tried again when the chat comes up.
+    Failed,
+    /// The bot left, was kicked or the chat was removed.
+    Left,
+}
+
+impl Membership {
+    fn as_str(self) -> &'static str {
+        match self {
+            Membership::Joining => "joining",
+            Membership::Joined => "joined",
+            Membership::Failed => "failed",
+            Membership::Left => "left",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "joining" => Some(Membership::Joining),
+            "joined" => Some(Membership::Joined),
+            "failed" => Some(Membership::Failed),
+            "left" => Some(Membership::Left),
+            _ => None,
+        }
+    }
+}
+
+/// What the bot knows about a chat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatRecord {
+    pub chat: ShipChat,
+    /// The group the chat belongs to, as `~ship/group-name`.
+    pub group: Option<String>,
+    pub status: Membership,
+    /// Unix time the status last changed.
+    pub updated_at: u64,
+}
+
+impl ChatRecord {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "ship": self.chat.ship_name.clone(),
+            "chat": self.chat.chat_name.clone(),
+            "group": self.group.clone(),
+            "status": self.status.as_str(),
+            "updated_at": self.updated_at,
+        }
+    }
+
+    fn from_bytes(value: &[u8]) -> Option<Self> {
+        let json = json::parse(std::str::from_utf8(value).ok()?).ok()?;
+        Some(ChatRecord {
+            chat: ShipChat {
+                ship_name: json["ship"].as_str()?.to_string(),
+                chat_name: json["chat"].as_str()?.to_string(),
+            },
+            group: json["group"].as_str().map(String::from),
+            status: Membership::parse(json["status"].as_str()?)?,
+            updated_at: json["updated_at"].as_u64().unwrap_or_default(),
+        })
+    }
+}
+
+/// The bot's persistent state, kept in an embedded sled database. Chats have
+/// their own tree, and other parts of the bot keep their state in further
+/// trees of the same database through `tree`.
+pub struct StateStore {
+    db: sled::Db,
+    chats: sled::Tree,
+}
+
+impl StateStore {
+    /// Open the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// A database that is deleted when dropped, for tests.
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        Self::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self, StoreError> {
+        let store = StateStore {
+            chats: db.open_tree("chats")?,
+            db,
+        };
+        // Joins cut short by a restart are tried again
+        for record in store.chats() {
+            if record.status == Membership::Joining {
+                store.set_chat(&record.chat, None, Membership::Failed)?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// A tree of the database for state that does not belong to chats.
+    pub fn tree(&self, name: &str) -> Result<sled::Tree, StoreError> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// What the bot knows about `chat`, if anything.
+    pub fn chat(&self, chat: &ShipChat) -> Option<ChatRecord> {
+        ChatRecord::from_bytes(&self.chats.get(chat.key()).ok()??)
+    }
+
+    /// Every chat the bot knows about.
+    pub fn chats(&self) -> Vec<ChatRecord> {
+        self.chats
+            .iter()
+            .values()
+            .filter_map(|value| ChatRecord::from_bytes(&value.ok()?))
+            .collect()
+    }
+
+    /// The chats the bot knows of in `group`, written as `~ship/group-name`.
+    pub fn chats_in_group(&self, group: &str) -> Vec<ChatRecord> {
+        let mut chats = self.chats();
+        chats.retain(|record| record.group.as_deref() == Some(group));
+        chats
+    }
+
+    /// Whether the bot should try to join `chat`, i.e. it is not in the chat
+    /// and is not in the middle of joining it.
+    pub fn should_join(&self, chat: &ShipChat) -> bool {
+        self.chat(chat)
+            .is_none_or(|record| matches!(record.status, Membership::Failed | Membership::Left))
+    }
+
+    /// Record the bot's membership of `chat`. Keeps the group from an earlier
+    /// record if `group` is not known.
+    pub fn set_chat(&self, chat: &ShipChat, group: Option<String>, status: Membership) -> Result<(), StoreError> {
+        let group = group.or_else(|| self.chat(chat).and_then(|record| record.group));
+        let record = ChatRecord {
+            chat: chat.clone(),
+            group,
+            status,
+            updated_at: unix_now(),
+        };
+        self.chats.insert(chat.key(), record.to_json().dump().as_bytes())?;
+        Ok(())
+    }
+
+    /// Record how a join of `chat` ended, unless the chat was left or joined
+    /// again in the meantime. Returns whether `status` was recorded.
+    pub fn finish_join(&self, chat: &ShipChat, status: Membership) -> Result<bool, StoreError> {
+        loop {
+            let current = match self.chats.get(chat.key())? {
+                Some(current) => current,
+                None => return Ok(false),
+            };
+            let mut record = match ChatRecord::from_bytes(&current) {
+                Some(record) if record.status == Membership::Joining => record,
+                _ => return Ok(false),
+            };
+            record.status = status;
+            record.updated_at = unix_now();
+            let new = record.to_json().dump();
+            // Retried if the record changed since it was read
+            if self.chats.compare_and_swap(chat.key(), Some(current), Some(new.as_bytes()))?.is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat;
+
+    #[test]
+    fn test_only_unknown_or_failed_chats_are_joined() {
+        let store = StateStore::temporary();
+        assert!(store.should_join(&chat("a")));
+
+        store.set_chat(&chat("a"), Some("~zod/group".to_string()), Membership::Joining).unwrap();
+        assert!(!store.should_join(&chat("a")));
+        store.set_chat(&chat("a"), None, Membership::Failed).unwrap();
+        assert!(store.should_join(&chat("a")));
+        store.set_chat(&chat("a"), None, Membership::Joined).unwrap();
+
+        let record = store.chat(&chat("a")).unwrap();
+        assert_eq!(record.status, Membership::Joined);
+        assert_eq!(record.group.as_deref(), Some("~zod/group"));
+        assert!(store.chat(&chat("b")).is_none());
+        assert_eq!(store.chats(), vec![record]);
+
+        assert_eq!(store.chats_in_group("~zod/group").len(), 1);
+        assert!(store.chats_in_group("~zod/other").is_empty());
+        store.set_chat(&chat("a"), None, Membership::Left).unwrap();
+        assert!(store.should_join(&chat("a")));
+    }
+
+    #[test]
+    fn test_joins_left_meanwhile_stay_left() {
+        let store = StateStore::temporary();
+        store.set_chat(&chat("a"), None, Membership::Joining).unwrap();
+        assert!(store.finish_join(&chat("a"), Membership::Joined).unwrap());
+        assert_eq!(store.chat(&chat("a")).unwrap().status, Membership::Joined);
+
+        store.set_chat(&chat("a"), None, Membership::Joining).unwrap();
+        store.set_chat(&chat("a"), None, Membership::Left).unwrap();
+        assert!(!store.finish_join(&chat("a"), Membership::Joined).unwrap());
+        assert_eq!(store.chat(&chat("a")).unwrap().status, Membership::Left);
+        assert!(!store.finish_join(&chat("b"), Membership::Failed).unwrap());
+        assert!(store.chat(&chat("b")).is_none());
+    }
+}
@@ -0,0 +1,1076 @@
+use chrono::{DateTime, Utc};
+use dotenv::dotenv;
+use json::object;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use url::Url;
+
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use s3::signing;
+
+use crate::bot::ShipChat;
+use crate::interval::Interval;
+use crate::outbox::unix_now;
+use crate::state_store::StateStore;
+
+/// Tree of the state database holding the S3 settings from the ship.
+const SHIP_S3_TREE: &str = "ship_s3";
+/// Key of those settings in the tree.
+const SHIP_S3_KEY: &str = "settings";
+/// Tree of the state database mapping image hashes to their uploads.
+const UPLOADED_TREE: &str = "uploaded_images";
+/// Tree of the state database mapping the keys of uploads to their hashes.
+const UPLOADED_KEYS_TREE: &str = "uploaded_image_keys";
+/// Longest symbol kept in an image key.
+const MAX_KEY_SYMBOL: usize = 40;
+/// Prefix of every chart image key.
+pub const CHART_PREFIX: &str = "charts/";
+/// Prefix of the charts posted with private links. It is kept inside
+/// `CHART_PREFIX` so retention covers them too, and bucket policies that
+/// allow public reads of `CHART_PREFIX` must leave it out.
+pub const PRIVATE_CHART_PREFIX: &str = "charts/private/";
+/// Id of the lifecycle rule the bot installs on S3 buckets.
+const LIFECYCLE_RULE_ID: &str = "urbit-bot-chart-retention";
+/// Longest a presigned link can stay valid on S3, in seconds (7 days).
+pub const MAX_PRESIGN_EXPIRY: u64 = 7 * 24 * 60 * 60;
+/// How long a link to a reused upload stays valid at least, before the
+/// retention job or a lifecycle rule deletes the image. Lifecycle rules run
+/// about once a day, and a day is left for chat members to open the link.
+const REUSE_MARGIN: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+/// Requests the built-in web server answers at the same time.
+const SERVER_THREADS: usize = 4;
+/// How long the built-in web server waits on a slow client.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request line the built-in web server reads.
+const MAX_REQUEST_LINE: u64 = 2048;
+
+/// An image could not be stored.
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<s3::S3Error> for StorageError {
+    fn from(e: s3::S3Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+/// An image in an `ImageStore`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredImage {
+    pub key: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unix time the image was last written.
+    pub modified: u64,
+}
+
+/// Who a link to an image should work for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone, for as long as the image is kept.
+    Public,
+    /// Only for a while, through a presigned link, where the backend can
+    /// presign links. Other backends link privately posted images publicly.
+    Private,
+}
+
+/// The chats whose charts get private links, from the `storage` section of
+/// the config.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrivateLinks {
+    /// Link every image privately, for buckets that do not allow public reads.
+    pub everywhere: bool,
+    /// Chats written as `~ship/chat-name` and groups written as
+    /// `~ship/group-name`, whose chats all get private links.
+    pub chats: Vec<String>,
+}
+
+impl PrivateLinks {
+    /// How images posted in `chat`, part of `group` if it is known, are linked.
+    pub fn access(&self, chat: &ShipChat, group: Option<&str>) -> Access {
+        let listed = |name: &str| self.chats.iter().any(|listed| listed == name);
+        if self.everywhere || listed(&chat.key()) || group.is_some_and(listed) {
+            Access::Private
+        } else {
+            Access::Public
+        }
+    }
+}
+
+/// Where rendered charts are kept. Each backend hands out the URL that is
+/// posted in the chat.
+pub trait ImageStore: Send + Sync {
+    /// Store `png` under `key`, a relative path such as `btcusd.png`, and
+    /// return its URL with `access`.
+    fn put_png(&self, key: &str, png: &[u8], access: Access) -> Result<String, StorageError>;
+
+    /// The URL to post for the image already stored under `key`.
+    fn link(&self, key: &str, access: Access) -> Result<String, StorageError>;
+
+    /// Every image whose key starts with `prefix`.
+    fn list(&self, prefix: &str) -> Result<Vec<StoredImage>, StorageError>;
+
+    fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Have the backend itself delete images under `prefix` once they are
+    /// `days` old, for backends that can.
+    fn expire_after(&self, _prefix: &str, _days: u32) -> Result<(), StorageError> {
+        Err(StorageError("this storage backend cannot expire images by itself".to_string()))
+    }
+
+    /// Stop reusing uploads made before `cutoff`, as they may be deleted
+    /// by the backend without the bot knowing.
+    fn forget_uploads_before(&self, _cutoff: u64) {}
+}
+
+/// Which `ImageStore` to use, from the `storage` section of the config.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageConfig {
+    /// Amazon S3, or an S3 compatible service if `endpoint` is set. The
+    /// bucket, region and keys come from `.env`.
+    S3 {
+        endpoint: Option<String>,
+        /// Address the bucket as `endpoint/bucket` rather than `bucket.endpoint`.
+        path_style: bool,
+        /// URL the bucket is published at, e.g. a CDN. Defaults to the bucket's own URL.
+        public_url: Option<String>,
+        /// How long private links stay valid.
+        presign_expiry: Duration,
+    },
+    /// The S3 bucket and credentials set up in Landscape on the bot's ship.
+    Ship {
+        path_style: bool,
+        public_url: Option<String>,
+        presign_expiry: Duration,
+    },
+    /// A directory on this machine.
+    Local {
+        dir: PathBuf,
+        /// Address the built-in web server listens on, if it is used.
+        listen: Option<String>,
+        /// URL the directory is published at.
+        public_url: String,
+    },
+}
+
+fn env_var(name: &str) -> Result<String, StorageError> {
+    env::var(name).map_err(|_| StorageError(format!("`{}` is not set in `.env`", name)))
+}
+
+/// `text` reduced to lowercase letters, digits and single dashes, so user
+/// input can go into an object key.
+fn sanitize(text: &str) -> String {
+    let mut clean = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            clean.push(c.to_ascii_lowercase());
+        } else if !clean.is_empty() && !clean.ends_with('-') {
+            clean.push('-');
+        }
+    }
+    clean.truncate(MAX_KEY_SYMBOL);
+    clean.trim_end_matches('-').to_string()
+}
+
+/// `interval` as written in image keys. Keys are lowercase, so months are
+/// written `mo` rather than `M`, which would read as minutes.
+fn interval_token(interval: Interval) -> String {
+    match interval {
+        Interval::Months(n) => format!("{}mo", n),
+        other => other.to_string().to_lowercase(),
+    }
+}
+
+/// Hex SHA-256 of an image.
+fn content_hash(png: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(png))
+}
+
+/// The key a chart linked with `access` is stored under, e.g.
+/// `charts/2021/03/14/bitstamp-btcusd_4h_0f3c9a1b2d4e5f60.png`, or
+/// `charts/private/2021/...` for private links. It only holds sanitized text
+/// and the start of the image's hash, so identical charts get the same key.
+pub fn chart_key(symbol: &str, interval: Interval, rendered_at: DateTime<Utc>, png: &[u8], access: Access) -> String {
+    let prefix = match access {
+        Access::Public => CHART_PREFIX,
+        Access::Private => PRIVATE_CHART_PREFIX,
+    };
+    format!(
+        "{}{}/{}_{}_{}.png",
+        prefix,
+        rendered_at.format("%Y/%m/%d"),
+        sanitize(symbol),
+        interval_token(interval),
+        &content_hash(png)[..16]
+    )
+}
+
+/// Hex HMAC-SHA256 of `message`, as used to sign S3 requests.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Result<String, StorageError> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).map_err(|e| StorageError(e.to_string()))?;
+    mac.update(message);
+    Ok(format!("{:x}", mac.finalize().into_bytes()))
+}
+
+/// An S3 lifecycle configuration with the rules of `existing`, the bucket's
+/// current configuration if it has one, plus a rule deleting objects under
+/// `prefix` after `days`. A rule added by an earlier call is replaced.
+fn lifecycle_xml(existing: Option<&str>, prefix: &str, days: u32) -> String {
+    let ours = format!("<ID>{}</ID>", LIFECYCLE_RULE_ID);
+    let mut rules = String::new();
+    let mut rest = existing.unwrap_or_default();
+    while let (Some(start), Some(end)) = (rest.find("<Rule>"), rest.find("</Rule>")) {
+        if start > end {
+            break;
+        }
+        let rule = &rest[start..end + "</Rule>".len()];
+        if !rule.contains(&ours) {
+            rules.push_str(rule);
+        }
+        rest = &rest[end + "</Rule>".len()..];
+    }
+    format!(
+        "<LifecycleConfiguration>{}<Rule>{}<Filter><Prefix>{}</Prefix></Filter>\
+         <Status>Enabled</Status><Expiration><Days>{}</Days></Expiration></Rule></LifecycleConfiguration>",
+        rules, ours, prefix, days
+    )
+}
+
+/// A presigned `url` with the slashes of its key written out. rust-s3 escapes
+/// them as `%2F`, which S3 compatible services do not all read back as `/`.
+/// The signature covers the path with its slashes unescaped, so it still
+/// matches.
+fn unescape_slashes(url: &str) -> String {
+    match url.split_once('?') {
+        Some((path, query)) => format!("{}?{}", path.replace("%2F", "/"), query),
+        None => url.replace("%2F", "/"),
+    }
+}
+
+/// The URL of `key` under `base`.
+fn join_url(base: &str, key: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), key.trim_start_matches('/'))
+}
+
+/// Open the image store described by `config`, starting its web server if
+/// it has one. Images already uploaded are not uploaded again, unless they
+/// are about to be deleted for being older than `max_age`.
+pub fn open(config: &StorageConfig, max_age: Option<Duration>, store: &StateStore) -> Result<Arc<dyn ImageStore>, StorageError> {
+    Ok(Arc::new(Deduplicated {
+        images: open_backend(config, store)?,
+        uploaded: store.tree(UPLOADED_TREE).map_err(|e| StorageError(e.to_string()))?,
+        uploaded_keys: store.tree(UPLOADED_KEYS_TREE).map_err(|e| StorageError(e.to_string()))?,
+        max_age,
+    }))
+}
+
+fn open_backend(config: &StorageConfig, store: &StateStore) -> Result<Arc<dyn ImageStore>, StorageError> {
+    match config {
+        StorageConfig::S3 {
+            endpoint,
+            path_style,
+            public_url,
+            presign_expiry,
+        } => {
+            dotenv().ok();
+            let credentials = Credentials::new(
+                Some(&env_var("AWS_ID")?),
+                Some(&env_var("AWS_SECRET")?),
+                None,
+                None,
+                None,
+            )
+            .map_err(|e| StorageError(e.to_string()))?;
+            let store = S3Store::new(
+                &env_var("S3_BUCKET")?,
+                &env_var("S3_REGION")?,
+                endpoint.as_deref(),
+                *path_style,
+                public_url.as_deref(),
+                *presign_expiry,
+                credentials,
+            )?;
+            Ok(Arc::new(store))
+        }
+        StorageConfig::Ship {
+            path_style,
+            public_url,
+            presign_expiry,
+        } => Ok(Arc::new(ShipS3Store {
+            settings: store.tree(SHIP_S3_TREE).map_err(|e| StorageError(e.to_string()))?,
+            path_style: *path_style,
+            public_url: public_url.clone(),
+            presign_expiry: *presign_expiry,
+            current: Mutex::new(None),
+        })),
+        StorageConfig::Local { dir, listen, public_url } => {
+            let store = LocalStore::new(dir.clone(), public_url.clone())?;
+            if let Some(address) = listen {
+                store.serve(address)?;
+            }
+            Ok(Arc::new(store))
+        }
+    }
+}
+
+/// Amazon S3 or an S3 compatible service such as MinIO. Public links point
+/// straight at the bucket, so it must allow public reads unless every link
+/// is private.
+pub struct S3Store {
+    bucket: Bucket,
+    public_url: String,
+    /// Seconds private links stay valid.
+    presign_expiry: u32,
+}
+
+impl S3Store {
+    /// A store for `bucket` in `region`. Without an `endpoint` this is Amazon
+    /// S3, otherwise the service at `endpoint`.
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        path_style: bool,
+        public_url: Option<&str>,
+        presign_expiry: Duration,
+        credentials: Credentials,
+    ) -> Result<Self, StorageError> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region.to_string(),
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+            },
+            None => region.parse().map_err(|e| StorageError(format!("invalid region `{}`: {}", region, e)))?,
+        };
+        let mut bucket = Bucket::new(bucket, region, credentials)?;
+        // Amazon S3 is always addressed by subdomain
+        if path_style && endpoint.is_some() {
+            bucket.set_path_style();
+        }
+        let public_url = match public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None if endpoint.is_none() => format!("https://{}.s3.{}.amazonaws.com", bucket.name(), bucket.region()),
+            None => bucket.url(),
+        };
+        Ok(S3Store {
+            bucket,
+            public_url,
+            presign_expiry: presign_expiry.as_secs().clamp(1, MAX_PRESIGN_EXPIRY) as u32,
+        })
+    }
+
+    /// Send `body` to the bucket's lifecycle configuration with `method`,
+    /// returning the status and answer. rust-s3 has no call for this, so the
+    /// request is signed here.
+    fn lifecycle_request(&self, method: &str, body: &str) -> Result<(u16, String), StorageError> {
+        let invalid = |e: &dyn fmt::Display| StorageError(e.to_string());
+        let mut url = Url::parse(&self.bucket.url()).map_err(|e| invalid(&e))?;
+        url.set_query(Some("lifecycle"));
+        let now = Utc::now();
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let content_sha256 = format!("{:x}", Sha256::digest(body.as_bytes()));
+        let mut values = vec![
+            ("host", host),
+            ("x-amz-content-sha256", content_sha256.clone()),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        if !body.is_empty() {
+            values.push(("content-md5", base64::encode(md5::compute(body.as_bytes()).as_ref())));
+        }
+        for (name, value) in values {
+            headers.insert(name, value.parse().map_err(|e| invalid(&e))?);
+        }
+
+        let region = self.bucket.region();
+        let (access_key, secret_key) = match (self.bucket.access_key(), self.bucket.secret_key()) {
+            (Some(access_key), Some(secret_key)) => (access_key, secret_key),
+            _ => return Err(StorageError("lifecycle rules need S3 credentials".to_string())),
+        };
+        let canonical_request = signing::canonical_request(method, &url, &headers, &content_sha256);
+        let string_to_sign = signing::string_to_sign(&now, &region, &canonical_request);
+        let key = signing::signing_key(&now, &secret_key, &region, "s3")?;
+        let signature = hmac_sha256(&key, string_to_sign.as_bytes())?;
+        let signed_headers = signing::signed_header_string(&headers);
+        let authorization = signing::authorization_header(&access_key, &now, &region, &signed_headers, &signature);
+        headers.insert("authorization", authorization.parse().map_err(|e| invalid(&e))?);
+
+        let method = reqwest::Method::from_bytes(method.as_bytes()).map_err(|e| invalid(&e))?;
+        let response = reqwest::blocking::Client::new()
+            .request(method, url)
+            .headers(headers)
+            .body(body.to_string())
+            .send()
+            .map_err(|e| invalid(&e))?;
+        let code = response.status().as_u16();
+        Ok((code, response.text().map_err(|e| invalid(&e))?))
+    }
+}
+
+fn check_status(code: u16) -> Result<(), StorageError> {
+    if (200..300).contains(&code) {
+        Ok(())
+    } else {
+        Err(StorageError(format!("the bucket answered with status {}", code)))
+    }
+}
+
+impl ImageStore for S3Store {
+    /// Private images are uploaded with a private ACL, so that only
+    /// presigned links can read them, unless a bucket policy allows more.
+    fn put_png(&self, key: &str, png: &[u8], access: Access) -> Result<String, StorageError> {
+        let (_, code) = match access {
+            Access::Public => self.bucket.put_object_with_content_type_blocking(key, png, "image/png")?,
+            Access::Private => {
+                let mut bucket = self.bucket.clone();
+                bucket.add_header("x-amz-acl", "private");
+                bucket.put_object_with_content_type_blocking(key, png, "image/png")?
+            }
+        };
+        check_status(code)?;
+        self.link(key, access)
+    }
+
+    fn link(&self, key: &str, access: Access) -> Result<String, StorageError> {
+        match access {
+            Access::Private => Ok(unescape_slashes(&self.bucket.presign_get(key, self.presign_expiry)?)),
+            Access::Public => Ok(join_url(&self.public_url, key)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredImage>, StorageError> {
+        let mut images = vec![];
+        for (page, code) in self.bucket.list_blocking(prefix.to_string(), None)? {
+            check_status(code)?;
+            for object in page.contents {
+                let modified = DateTime::parse_from_rfc3339(&object.last_modified)
+                    .map_err(|e| StorageError(format!("invalid date for {}: {}", object.key, e)))?;
+                images.push(StoredImage {
+                    key: object.key,
+                    size: object.size,
+                    modified: modified.timestamp().max(0) as u64,
+                });
+            }
+        }
+        Ok(images)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let (_, code) = self.bucket.delete_object_blocking(key)?;
+        check_status(code)
+    }
+
+    /// Adds a lifecycle rule to the bucket. Setting the lifecycle replaces
+    /// every rule, so the current ones are read first and kept.
+    fn expire_after(&self, prefix: &str, days: u32) -> Result<(), StorageError> {
+        let existing = match self.lifecycle_request("GET", "")? {
+            (200, existing) => Some(existing),
+            // NoSuchLifecycleConfiguration
+            (404, _) => None,
+            (code, _) => return Err(StorageError(format!("the bucket answered with status {}", code))),
+        };
+        let (code, _) = self.lifecycle_request("PUT", &lifecycle_xml(existing.as_deref(), prefix, days))?;
+        check_status(code)
+    }
+}
+
+/// Stores each distinct image once for each access, remembering by content
+/// hash where earlier uploads went. Private images are kept apart, so that
+/// a public link never points at one and a private chart is never served
+/// from a public upload.
+struct Deduplicated {
+    images: Arc<dyn ImageStore>,
+    uploaded: sled::Tree,
+    /// Hash of each upload in `uploaded` by key, so a deleted image is
+    /// forgotten without reading every upload.
+    uploaded_keys: sled::Tree,
+    /// Age at which images are deleted, if they are.
+    max_age: Option<Duration>,
+}
+
+impl ImageStore for Deduplicated {
+    fn put_png(&self, key: &str, png: &[u8], access: Access) -> Result<String, StorageError> {
+        let hash = match access {
+            Access::Public => content_hash(png),
+            Access::Private => format!("private/{}", content_hash(png)),
+        };
+        let earlier = self.uploaded.get(&hash).ok().flatten();
+        if let Some(record) = earlier.and_then(|value| json::parse(std::str::from_utf8(&value).ok()?).ok()) {
+            // Uploads close to being deleted are uploaded again instead
+            let reuse_for = self.max_age.map(|max_age| max_age.saturating_sub(REUSE_MARGIN).as_secs());
+            let uploaded_at = record["uploaded_at"].as_u64().unwrap_or_default();
+            let fresh = reuse_for.is_none_or(|reuse_for| unix_now() < uploaded_at + reuse_for);
+            // Linked afresh, as presigned links expire
+            if let (Some(key), true) = (record["key"].as_str(), fresh) {
+                return self.images.link(key, access);
+            }
+        }
+        // Identical charts rendered at the same moment may both get here and
+        // be uploaded twice. That race is accepted: they usually share a key,
+        // so the second upload only overwrites the first with the same image,
+        // and an extra copy is deleted along with the other old images.
+        let url = self.images.put_png(key, png, access)?;
+        let record = object! { "key": key, "uploaded_at": unix_now() };
+        let recorded = self.uploaded.insert(hash.as_bytes(), record.dump().as_bytes());
+        if let Err(e) = recorded.and_then(|_| self.uploaded_keys.insert(key, hash.as_bytes())) {
+            println!("Failed to record the upload of {}: {}", key, e);
+        }
+        Ok(url)
+    }
+
+    fn link(&self, key: &str, access: Access) -> Result<String, StorageError> {
+        self.images.link(key, access)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredImage>, StorageError> {
+        self.images.list(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.images.delete(key)?;
+        if let Some(hash) = self.uploaded_keys.remove(key).ok().flatten() {
+            // The image may have been uploaded again under another key since
+            let record = self.uploaded.get(&hash).ok().flatten();
+            let record = record.and_then(|value| json::parse(std::str::from_utf8(&value).ok()?).ok());
+            if record.is_none_or(|record| record["key"] == key) {
+                self.uploaded.remove(hash).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn expire_after(&self, prefix: &str, days: u32) -> Result<(), StorageError> {
+        self.images.expire_after(prefix, days)
+    }
+
+    fn forget_uploads_before(&self, cutoff: u64) {
+        for (hash, value) in self.uploaded.iter().filter_map(Result::ok) {
+            let record = std::str::from_utf8(&value).ok().and_then(|value| json::parse(value).ok());
+            if let Some(record) = &record {
+                if record["uploaded_at"].as_u64().unwrap_or_default() >= cutoff {
+                    continue;
+                }
+                if let Some(key) = record["key"].as_str() {
+                    self.uploaded_keys.remove(key).ok();
+                }
+            }
+            self.uploaded.remove(hash).ok();
+        }
+    }
+}
+
+/// Merge an update from the ship's s3-store into the S3 settings kept in
+/// `store`. Landscape sends every setting when the bot subscribes, and the
+/// changed one whenever it is edited.
+pub fn record_ship_s3_update(store: &StateStore, update: &str) {
+    let update = match json::parse(update) {
+        Ok(update) => update["s3-update"].clone(),
+        Err(_) => return,
+    };
+    let tree = match store.tree(SHIP_S3_TREE) {
+        Ok(tree) => tree,
+        Err(e) => return println!("Failed to open the ship's S3 settings: {}", e),
+    };
+    let mut settings = tree
+        .get(SHIP_S3_KEY)
+        .ok()
+        .flatten()
+        .and_then(|value| json::parse(std::str::from_utf8(&value).ok()?).ok())
+        .unwrap_or_else(json::JsonValue::new_object);
+    let before = settings.dump();
+
+    let credentials = &update["credentials"];
+    let configuration = &update["configuration"];
+    let changes = [
+        ("endpoint", &credentials["endpoint"]),
+        ("accessKeyId", &credentials["accessKeyId"]),
+        ("secretAccessKey", &credentials["secretAccessKey"]),
+        ("currentBucket", &configuration["currentBucket"]),
+        ("region", &configuration["region"]),
+        ("endpoint", &update["setEndpoint"]),
+        ("accessKeyId", &update["setAccessKeyId"]),
+        ("secretAccessKey", &update["setSecretAccessKey"]),
+        ("currentBucket", &update["setCurrentBucket"]),
+        ("region", &update["setRegion"]),
+    ];
+    for (key, value) in changes.iter() {
+        if let Some(value) = value.as_str() {
+            settings[*key] = value.into();
+        }
+    }
+    if settings.dump() != before {
+        println!("The ship's S3 settings changed, chart images now go to bucket `{}`.", settings["currentBucket"]);
+        if let Err(e) = tree.insert(SHIP_S3_KEY, settings.dump().as_bytes()) {
+            println!("Failed to save the ship's S3 settings: {}", e);
+        }
+    }
+}
+
+/// The S3 bucket set up in Landscape on the bot's ship. The settings arrive
+/// through the bot's s3-store subscription, so uploads always use the current
+/// credentials without them being copied into `.env`.
+pub struct ShipS3Store {
+    settings: sled::Tree,
+    path_style: bool,
+    public_url: Option<String>,
+    presign_expiry: Duration,
+    /// The store built from the settings it was last used with.
+    current: Mutex<Option<(sled::IVec, Arc<S3Store>)>>,
+}
+
+impl ShipS3Store {
+    /// The store for the latest settings from the ship.
+    fn bucket(&self) -> Result<Arc<S3Store>, StorageError> {
+        let missing = || StorageError("the ship has no S3 storage set up in Landscape yet".to_string());
+        let value = self
+            .settings
+            .get(SHIP_S3_KEY)
+            .map_err(|e| StorageError(e.to_string()))?
+            .ok_or_else(missing)?;
+        let mut current = self.current.lock().unwrap();
+        if let Some((used, store)) = current.as_ref() {
+            if *used == value {
+                return Ok(Arc::clone(store));
+            }
+        }
+
+        let settings = json::parse(std::str::from_utf8(&value).map_err(|e| StorageError(e.to_string()))?)
+            .map_err(|e| StorageError(e.to_string()))?;
+        let setting = |key: &str| settings[key].as_str().filter(|value| !value.is_empty()).ok_or_else(missing);
+        let endpoint = setting("endpoint")?;
+        // Landscape accepts endpoints without a scheme
+        let endpoint = if endpoint.contains("://") {
+            endpoint.to_string()
+        } else {
+            format!("https://{}", endpoint)
+        };
+        let credentials = Credentials::new(
+            Some(setting("accessKeyId")?),
+            Some(setting("secretAccessKey")?),
+            None,
+            None,
+            None,
+        )
+        .map_err(|e| StorageError(e.to_string()))?;
+        let store = Arc::new(S3Store::new(
+            setting("currentBucket")?,
+            setting("region").unwrap_or("us-east-1"),
+            Some(&endpoint),
+            self.path_style,
+            self.public_url.as_deref(),
+            self.presign_expiry,
+            credentials,
+        )?);
+        *current = Some((value, Arc::clone(&store)));
+        Ok(store)
+    }
+}
+
+impl ImageStore for ShipS3Store {
+    fn put_png(&self, key: &str, png: &[u8], access: Access) -> Result<String, StorageError> {
+        self.bucket()?.put_png(key, png, access)
+    }
+
+    fn link(&self, key: &str, access: Access) -> Result<String, StorageError> {
+        self.bucket()?.link(key, access)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredImage>, StorageError> {
+        self.bucket()?.list(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.bucket()?.delete(key)
+    }
+
+    fn expire_after(&self, prefix: &str, days: u32) -> Result<(), StorageError> {
+        self.bucket()?.expire_after(prefix, days)
+    }
+}
+
+/// A directory on this machine, published by the bot's own web server or by
+/// another one.
+pub struct LocalStore {
+    dir: PathBuf,
+    public_url: String,
+}
+
+/// The path of `key` inside `dir`, refusing keys that would leave it.
+fn path_in(dir: &Path, key: &str) -> Option<PathBuf> {
+    let key = Path::new(key.trim_start_matches('/'));
+    let normal = key.components().all(|component| matches!(component, Component::Normal(_)));
+    if normal && key.components().next().is_some() {
+        Some(dir.join(key))
+    } else {
+        None
+    }
+}
+
+/// The media type of a file served by the built-in web server.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "html" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+impl LocalStore {
+    pub fn new(dir: PathBuf, public_url: String) -> Result<Self, StorageError> {
+        fs::create_dir_all(&dir)?;
+        Ok(LocalStore { dir, public_url })
+    }
+
+    /// Serve the directory over HTTP on `address` from a few background
+    /// threads, each answering one request at a time.
+    pub fn serve(&self, address: &str) -> Result<(), StorageError> {
+        let listener = TcpListener::bind(address)?;
+        println!("Serving chart images from `{}` on http://{}.", self.dir.display(), address);
+        for _ in 0..SERVER_THREADS {
+            let (listener, dir) = (listener.try_clone()?, self.dir.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming().filter_map(Result::ok) {
+                    if let Err(e) = Self::respond(&dir, stream) {
+                        println!("Failed to serve a chart image: {}", e);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Answer a single request for a file in `dir`.
+    fn respond(dir: &Path, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(SERVER_TIMEOUT))?;
+        stream.set_write_timeout(Some(SERVER_TIMEOUT))?;
+        let mut request_line = String::new();
+        BufReader::new((&stream).take(MAX_REQUEST_LINE)).read_line(&mut request_line)?;
+        if !request_line.ends_with('\n') {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+            // Read a little of what is left, so closing does not reset the
+            // connection before the client has the answer
+            stream.shutdown(Shutdown::Write)?;
+            std::io::copy(&mut (&stream).take(MAX_REQUEST_LINE * 32), &mut std::io::sink())?;
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        let path = target.split('?').next().and_then(|key| path_in(dir, key));
+        let file = match (method, path) {
+            ("GET", Some(path)) | ("HEAD", Some(path)) => fs::read(&path).ok().map(|body| (content_type(&path), body)),
+            _ => None,
+        };
+        match file {
+            Some((content_type, body)) => {
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content_type,
+                    body.len()
+                );
+                stream.write_all(header.as_bytes())?;
+                if method == "GET" {
+                    stream.write_all(&body)?;
+                }
+            }
+            None => stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?,
+        }
+        stream.flush()
+    }
+}
+
+impl ImageStore for LocalStore {
+    fn put_png(&self, key: &str, png: &[u8], access: Access) -> Result<String, StorageError> {
+        let path = path_in(&self.dir, key).ok_or_else(|| StorageError(format!("invalid image key `{}`", key)))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, png)?;
+        self.link(key, access)
+    }
+
+    /// Always a public link, as web servers cannot check presigned ones.
+    fn link(&self, key: &str, _access: Access) -> Result<String, StorageError> {
+        Ok(join_url(&self.public_url, key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StoredImage>, StorageError> {
+        let mut images = vec![];
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let relative = entry.path().strip_prefix(&self.dir).map(Path::to_path_buf).unwrap_or_default();
+                let key = relative.components().filter_map(|part| part.as_os_str().to_str()).collect::<Vec<_>>().join("/");
+                if key.starts_with(prefix) {
+                    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map(|age| age.as_secs());
+                    images.push(StoredImage {
+                        key,
+                        size: metadata.len(),
+                        modified: modified.unwrap_or_default(),
+                    });
+                }
+            }
+        }
+        Ok(images)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let path = path_in(&self.dir, key).ok_or_else(|| StorageError(format!("invalid image key `{}`", key)))?;
+        Ok(fs::remove_file(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{chat, TempDir};
+    use std::io::Read;
+
+    #[test]
+    fn test_local_store_serves_uploads() {
+        let dir = TempDir::new("images");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let store = LocalStore::new(dir.path().to_path_buf(), format!("http://{}/", address)).unwrap();
+        store.serve(&address).unwrap();
+        let url = store.put_png("2021/btcusd.png", b"png", Access::Private).unwrap();
+        assert_eq!(url, format!("http://{}/2021/btcusd.png", address));
+        assert!(store.put_png("../escape.png", b"png", Access::Public).is_err());
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let found = get("/2021/btcusd.png");
+        assert!(found.starts_with("HTTP/1.1 200 OK"));
+        assert!(found.contains("Content-Type: image/png\r\n"));
+        assert!(found.ends_with("\r\n\r\npng"));
+        assert!(get("/../Cargo.toml").starts_with("HTTP/1.1 404"));
+        assert!(get(&format!("/{}", "a".repeat(4096))).starts_with("HTTP/1.1 400"));
+        fs::write(dir.path().join("notes.txt"), "hi").unwrap();
+        assert!(get("/notes.txt").contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn test_ship_s3_settings_follow_updates() {
+        let store = StateStore::temporary();
+        let config = StorageConfig::Ship {
+            path_style: true,
+            public_url: None,
+            presign_expiry: Duration::from_secs(3600),
+        };
+        let images = ShipS3Store {
+            settings: store.tree(SHIP_S3_TREE).unwrap(),
+            path_style: true,
+            public_url: None,
+            presign_expiry: Duration::from_secs(3600),
+            current: Mutex::new(None),
+        };
+        assert!(open(&config, None, &store).is_ok());
+        assert!(images.bucket().is_err());
+
+        let credentials = r#"{"s3-update": {"credentials": {"endpoint": "localhost:9000", "accessKeyId": "id", "secretAccessKey": "secret"}}}"#;
+        record_ship_s3_update(&store, credentials);
+        record_ship_s3_update(&store, r#"{"s3-update": {"configuration": {"buckets": ["a", "b"], "currentBucket": "a"}}}"#);
+        assert_eq!(images.bucket().unwrap().public_url, "https://localhost:9000/a");
+        record_ship_s3_update(&store, r#"{"s3-update": {"setCurrentBucket": "b"}}"#);
+        assert_eq!(images.bucket().unwrap().public_url, "https://localhost:9000/b");
+    }
+
+    /// Counts uploads instead of storing anything.
+    struct Counting(Mutex<usize>);
+
+    impl ImageStore for Counting {
+        fn put_png(&self, key: &str, _png: &[u8], access: Access) -> Result<String, StorageError> {
+            *self.0.lock().unwrap() += 1;
+            self.link(key, access)
+        }
+
+        fn link(&self, key: &str, access: Access) -> Result<String, StorageError> {
+            match access {
+                Access::Public => Ok(join_url("https://images", key)),
+                Access::Private => Ok(format!("{}?signed", join_url("https://images", key))),
+            }
+        }
+
+        fn list(&self, _prefix: &str) -> Result<Vec<StoredImage>, StorageError> {
+            Ok(vec![])
+        }
+
+        fn delete(&self, _key: &str) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chart_keys() {
+        let rendered_at = DateTime::parse_from_rfc3339("2021-03-14T15:09:26Z").unwrap().with_timezone(&Utc);
+        let key = chart_key("BITSTAMP:BTCUSD", Interval::Hours(4), rendered_at, b"png", Access::Public);
+        assert!(key.starts_with("charts/2021/03/14/bitstamp-btcusd_4h_"));
+        assert_eq!(key, chart_key("BITSTAMP:BTCUSD", Interval::Hours(4), rendered_at, b"png", Access::Public));
+        assert_ne!(key, chart_key("BITSTAMP:BTCUSD", Interval::Hours(4), rendered_at, b"other png", Access::Public));
+        let private = chart_key("BITSTAMP:BTCUSD", Interval::Hours(4), rendered_at, b"png", Access::Private);
+        assert!(private.starts_with("charts/private/2021/03/14/bitstamp-btcusd_4h_"));
+        // Minutes and months differ only by case in chat
+        let minute = chart_key("BTCUSD", Interval::Minutes(1), rendered_at, b"png", Access::Public);
+        let month = chart_key("BTCUSD", Interval::Months(1), rendered_at, b"png", Access::Public);
+        assert!(minute.starts_with("charts/2021/03/14/btcusd_1m_"));
+        assert!(month.starts_with("charts/2021/03/14/btcusd_1mo_"));
+        assert_ne!(minute, month);
+        assert_eq!(sanitize("../ ?x=<b>&y  "), "x-b-y");
+        assert_eq!(sanitize(&"a".repeat(100)).len(), MAX_KEY_SYMBOL);
+    }
+
+    #[test]
+    fn test_identical_images_are_stored_once() {
+        let store = StateStore::temporary();
+        let counting = Arc::new(Counting(Mutex::new(0)));
+        let images = Deduplicated {
+            images: counting.clone(),
+            uploaded: store.tree(UPLOADED_TREE).unwrap(),
+            uploaded_keys: store.tree(UPLOADED_KEYS_TREE).unwrap(),
+            max_age: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+        };
+        let first = images.put_png("a.png", b"png", Access::Public).unwrap();
+        assert_eq!(images.put_png("b.png", b"png", Access::Public).unwrap(), first);
+        // Private images are uploaded apart from public ones
+        let private = images.put_png("private/b.png", b"png", Access::Private).unwrap();
+        assert_eq!(private, "https://images/private/b.png?signed");
+        assert_eq!(images.put_png("private/c.png", b"png", Access::Private).unwrap(), private);
+        images.put_png("c.png", b"other png", Access::Public).unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 3);
+
+        // Deleted images are uploaded again
+        images.delete("a.png").unwrap();
+        images.put_png("d.png", b"png", Access::Public).unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 4);
+        assert_eq!(images.put_png("private/d.png", b"png", Access::Private).unwrap(), private);
+        images.forget_uploads_before(unix_now() + 1);
+        assert!(images.uploaded_keys.is_empty());
+        images.put_png("e.png", b"other png", Access::Public).unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 5);
+
+        // Images deleted soon are uploaded again
+        let old = object! { "key": "e.png", "uploaded_at": unix_now() - 29 * 24 * 60 * 60 };
+        images.uploaded.insert(content_hash(b"other png"), old.dump().as_bytes()).unwrap();
+        assert_eq!(images.put_png("f.png", b"other png", Access::Public).unwrap(), "https://images/f.png");
+        assert_eq!(*counting.0.lock().unwrap(), 6);
+        // Deleting the replaced upload keeps the new one
+        images.delete("e.png").unwrap();
+        assert_eq!(images.put_png("g.png", b"other png", Access::Public).unwrap(), "https://images/f.png");
+    }
+
+    #[test]
+    fn test_lifecycle_signature_parts() {
+        // RFC 4231, test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap();
+        assert_eq!(mac, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let xml = lifecycle_xml(None, "charts/", 30);
+        assert!(xml.contains("<Prefix>charts/</Prefix>") && xml.contains("<Days>30</Days>"));
+
+        // Other rules are kept, and the bot's own rule is replaced
+        let other = "<Rule><ID>logs</ID><Filter><Prefix>logs/</Prefix></Filter><Status>Enabled</Status></Rule>";
+        let existing = format!(r#"<?xml version="1.0"?><LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}{}</LifecycleConfiguration>"#, other, xml);
+        let merged = lifecycle_xml(Some(&existing), "charts/", 7);
+        assert!(merged.contains(other));
+        assert_eq!(merged.matches("<Rule>").count(), 2);
+        assert!(merged.contains("<Days>7</Days>") && !merged.contains("<Days>30</Days>"));
+    }
+
+    #[test]
+    fn test_s3_urls() {
+        let credentials = || Credentials::new(Some("id"), Some("secret"), None, None, None).unwrap();
+        let expiry = Duration::from_secs(3600);
+        let aws = S3Store::new("charts", "eu-west-2", None, false, None, expiry, credentials()).unwrap();
+        assert_eq!(aws.public_url, "https://charts.s3.eu-west-2.amazonaws.com");
+        let aws = S3Store::new("charts", "eu-west-2", None, true, None, expiry, credentials()).unwrap();
+        assert!(!aws.bucket.is_path_style());
+        let minio = S3Store::new("charts", "us-east-1", Some("http://localhost:9000/"), true, None, expiry, credentials()).unwrap();
+        assert_eq!(minio.public_url, "http://localhost:9000/charts");
+        assert!(minio.bucket.is_path_style());
+        let cdn = Some("https://cdn.example.com/");
+        let behind_cdn = S3Store::new("charts", "us-east-1", Some("http://localhost:9000"), true, cdn, expiry, credentials()).unwrap();
+        assert_eq!(behind_cdn.link("a.png", Access::Public).unwrap(), "https://cdn.example.com/a.png");
+    }
+
+    #[test]
+    fn test_presigned_links() {
+        let credentials = Credentials::new(Some("id"), Some("secret"), None, None, None).unwrap();
+        let expiry = Duration::from_secs(3600);
+        let store = S3Store::new("charts", "us-east-1", Some("http://localhost:9000"), true, None, expiry, credentials).unwrap();
+        let link = store.link("charts/a.png", Access::Private).unwrap();
+        assert!(link.starts_with("http://localhost:9000/charts/charts/a.png?"));
+        assert!(link.contains("X-Amz-Expires=3600"));
+        assert!(link.contains("X-Amz-Signature="));
+
+        // The signed path is the same with or without the slashes escaped
+        let signed = store.bucket.presign_get("charts/a.png", 3600).unwrap();
+        assert!(signed.contains("/charts/charts%2Fa.png?"));
+        let canonical = |url: &str| signing::canonical_uri_string(&Url::parse(url).unwrap());
+        assert_eq!(canonical(&link), canonical(&signed));
+        assert_eq!(canonical(&link), "/charts/charts/a.png");
+    }
+
+    #[test]
+    fn test_private_links() {
+        let links = PrivateLinks {
+            everywhere: false,
+            chats: vec!["~zod/premium".to_string(), "~zod/traders".to_string()],
+        };
+        assert_eq!(links.access(&chat("premium"), None), Access::Private);
+        assert_eq!(links.access(&chat("lobby"), Some("~zod/traders")), Access::Private);
+        assert_eq!(links.access(&chat("lobby"), Some("~zod/public")), Access::Public);
+        assert_eq!(links.access(&chat("lobby"), None), Access::Public);
+        let everywhere = PrivateLinks {
+            everywhere: true,
+            chats: vec![],
+        };
+        assert_eq!(everywhere.access(&chat("lobby"), None), Access::Private);
+    }
+}
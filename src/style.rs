@@ -0,0 +1,159 @@
+/// Colour scheme of the chart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+/// How the price series is drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeriesStyle {
+    Bars,
+    Candles,
+    HollowCandles,
+    HeikinAshi,
+    Line,
+    Area,
+    Baseline,
+    Renko,
+    Kagi,
+    PointAndFigure,
+    LineBreak,
+}
+
+impl SeriesStyle {
+    /// The value TradingView's widget expects in its `style` parameter.
+    pub fn tradingview_id(self) -> u8 {
+        match self {
+            SeriesStyle::Bars => 0,
+            SeriesStyle::Candles => 1,
+            SeriesStyle::Line => 2,
+            SeriesStyle::Area => 3,
+            SeriesStyle::Renko => 4,
+            SeriesStyle::Kagi => 5,
+            SeriesStyle::PointAndFigure => 6,
+            SeriesStyle::LineBreak => 7,
+            SeriesStyle::HeikinAshi => 8,
+            SeriesStyle::HollowCandles => 9,
+            SeriesStyle::Baseline => 10,
+        }
+    }
+}
+
+/// Size presets for the screenshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChartSize {
+    Default,
+    /// Wide screens and desktop chat windows.
+    Wide,
+    /// Phones, where a square image reads best.
+    Square,
+    Tall,
+}
+
+impl ChartSize {
+    /// Viewport width and height in pixels.
+    pub fn dimensions(self) -> (u16, u16) {
+        match self {
+            ChartSize::Default => (1024, 800),
+            ChartSize::Wide => (1600, 800),
+            ChartSize::Square => (900, 900),
+            ChartSize::Tall => (800, 1200),
+        }
+    }
+}
+
+/// How a chart looks, set with words such as `--light`, `heikin`, `--log`
+/// or `wide` after the timeframe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChartStyle {
+    pub theme: Theme,
+    pub series: SeriesStyle,
+    pub log_scale: bool,
+    pub size: ChartSize,
+}
+
+impl Default for ChartStyle {
+    fn default() -> Self {
+        ChartStyle {
+            theme: Theme::Dark,
+            series: SeriesStyle::Candles,
+            log_scale: false,
+            size: ChartSize::Default,
+        }
+    }
+}
+
+impl ChartStyle {
+    /// Apply a style option. Returns `false` if `word` is not a style option.
+    /// Leading dashes are optional, so `--light` and `light` are the same, and
+    /// an em dash (which some keyboards turn `--` into) works as well.
+    pub fn apply(&mut self, word: &str) -> bool {
+        let option = word.trim_start_matches(['-', '—']).to_lowercase();
+        match option.as_str() {
+            "light" => self.theme = Theme::Light,
+            "dark" => self.theme = Theme::Dark,
+            "log" => self.log_scale = true,
+            "bars" | "ohlc" => self.series = SeriesStyle::Bars,
+            "candles" | "candle" => self.series = SeriesStyle::Candles,
+            "hollow" => self.series = SeriesStyle::HollowCandles,
+            "heikin" | "heikinashi" | "ha" => self.series = SeriesStyle::HeikinAshi,
+            "line" => self.series = SeriesStyle::Line,
+            "area" => self.series = SeriesStyle::Area,
+            "baseline" => self.series = SeriesStyle::Baseline,
+            "renko" => self.series = SeriesStyle::Renko,
+            "kagi" => self.series = SeriesStyle::Kagi,
+            "pnf" => self.series = SeriesStyle::PointAndFigure,
+            "linebreak" => self.series = SeriesStyle::LineBreak,
+            "wide" => self.size = ChartSize::Wide,
+            "square" | "mobile" => self.size = ChartSize::Square,
+            "tall" => self.size = ChartSize::Tall,
+            _ => return false,
+        }
+        true
+    }
+
+    /// The TradingView widget parameters for this style.
+    pub fn url_params(&self) -> Vec<(&'static str, String)> {
+        let theme = match self.theme {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        };
+        let mut params = vec![
+            ("theme", theme.to_string()),
+            ("style", self.series.tradingview_id().to_string()),
+        ];
+        if self.log_scale {
+            params.push((
+                "overrides",
+                r#"{"mainSeriesProperties.priceAxisProperties.log":true}"#.to_string(),
+            ));
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_options() {
+        let mut style = ChartStyle::default();
+        assert!(style.apply("--light"));
+        assert!(style.apply("heikin"));
+        assert!(style.apply("—log"));
+        assert!(style.apply("square"));
+        assert!(!style.apply("rsi"));
+        assert_eq!(
+            style,
+            ChartStyle {
+                theme: Theme::Light,
+                series: SeriesStyle::HeikinAshi,
+                log_scale: true,
+                size: ChartSize::Square,
+            }
+        );
+        assert_eq!(style.url_params()[..2], [("theme", "light".to_string()), ("style", "8".to_string())]);
+    }
+}
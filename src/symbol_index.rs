@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::chart_request::Symbol;
+
+/// A symbol known to the local index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolEntry {
+    pub symbol: Symbol,
+    pub description: String,
+}
+
+/// The index file could not be read or is malformed.
+#[derive(Debug)]
+pub struct IndexError(String);
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+/// A local list of tradable symbols, used to reject unknown symbols without
+/// launching a browser and to suggest what the user might have meant.
+///
+/// Loaded from CSV (`exchange,ticker,description` per line, with an optional
+/// header and `#` comments) or from JSON (an array of objects with
+/// `exchange`, `ticker` and `description`).
+#[derive(Clone, Debug, Default)]
+pub struct SymbolIndex {
+    entries: Vec<SymbolEntry>,
+}
+
+/// Levenshtein distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn entry(exchange: &str, ticker: &str, description: &str) -> Result<SymbolEntry, IndexError> {
+    let symbol: Symbol = format!("{}:{}", exchange.trim(), ticker.trim())
+        .parse()
+        .map_err(|e| IndexError(format!("invalid symbol `{}:{}`: {}", exchange, ticker, e)))?;
+    Ok(SymbolEntry {
+        symbol,
+        description: description.trim().to_string(),
+    })
+}
+
+impl SymbolIndex {
+    pub fn new(entries: Vec<SymbolEntry>) -> Self {
+        SymbolIndex { entries }
+    }
+
+    pub fn from_csv_str(csv: &str) -> Result<SymbolIndex, IndexError> {
+        let mut entries = vec![];
+        for (number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Only the first two commas split columns, so descriptions may contain commas
+            let mut columns = line.splitn(3, ',');
+            let exchange = columns.next().unwrap_or_default();
+            let ticker = columns
+                .next()
+                .ok_or_else(|| IndexError(format!("line {}: expected `exchange,ticker`", number + 1)))?;
+            if number == 0 && exchange.trim().eq_ignore_ascii_case("exchange") {
+                continue;
+            }
+            entries.push(entry(exchange, ticker, columns.next().unwrap_or_default())?);
+        }
+        Ok(SymbolIndex::new(entries))
+    }
+
+    pub fn from_json_str(json_str: &str) -> Result<SymbolIndex, IndexError> {
+        let json = json::parse(json_str).map_err(|e| IndexError(e.to_string()))?;
+        if !json.is_array() {
+            return Err(IndexError("expected an array of symbols".to_string()));
+        }
+        let mut entries = vec![];
+        for item in json.members() {
+            match (item["exchange"].as_str(), item["ticker"].as_str()) {
+                (Some(exchange), Some(ticker)) => entries.push(entry(
+                    exchange,
+                    ticker,
+                    item["description"].as_str().unwrap_or_default(),
+                )?),
+                _ => return Err(IndexError(format!("symbol without exchange or ticker: {}", item))),
+            }
+        }
+        Ok(SymbolIndex::new(entries))
+    }
+
+    /// Load an index from a `.json` or `.csv` file.
+    pub fn load(path: &Path) -> Result<SymbolIndex, IndexError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| IndexError(format!("could not read {}: {}", path.display(), e)))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::from_json_str(&contents),
+            _ => Self::from_csv_str(&contents),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether `symbol` is in the index. A symbol without an exchange matches
+    /// the ticker on any exchange.
+    pub fn contains(&self, symbol: &Symbol) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.symbol.ticker == symbol.ticker
+                && (symbol.exchange.is_none() || entry.symbol.exchange == symbol.exchange)
+        })
+    }
+
+    /// The closest known symbols to `symbol` by edit distance.
+    pub fn suggest(&self, symbol: &Symbol, limit: usize) -> Vec<&SymbolEntry> {
+        let max_distance = (symbol.ticker.len() / 3).max(1);
+        let mut matches: Vec<(usize, &SymbolEntry)> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let mut distance = edit_distance(&symbol.ticker, &entry.symbol.ticker);
+                if symbol.exchange.is_some() && entry.symbol.exchange != symbol.exchange {
+                    distance += 1;
+                }
+                (distance, entry)
+            })
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        matches.sort_by_key(|(distance, entry)| (*distance, entry.symbol.to_string()));
+        matches.into_iter().take(limit).map(|(_, entry)| entry).collect()
+    }
+
+    /// Symbols matching a free-text query: exact tickers first, then tickers
+    /// starting with or containing the query, then description matches, then
+    /// near misses by edit distance.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&SymbolEntry> {
+        let query = query.to_uppercase();
+        let max_distance = (query.len() / 3).max(1);
+        let mut matches: Vec<(usize, usize, &SymbolEntry)> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let ticker = &entry.symbol.ticker;
+                let distance = edit_distance(&query, ticker);
+                let rank = if *ticker == query {
+                    0
+                } else if ticker.starts_with(&query) {
+                    1
+                } else if ticker.contains(&query) {
+                    2
+                } else if entry.description.to_uppercase().contains(&query) {
+                    3
+                } else if distance <= max_distance {
+                    4
+                } else {
+                    return None;
+                };
+                Some((rank, distance, entry))
+            })
+            .collect();
+        matches.sort_by_key(|(rank, distance, entry)| (*rank, *distance, entry.symbol.to_string()));
+        matches.into_iter().take(limit).map(|(_, _, entry)| entry).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "exchange,ticker,description
+# Crypto
+BINANCE,BTCUSDT,Bitcoin / TetherUS
+BITSTAMP,BTCUSD,Bitcoin / U.S. dollar
+COINBASE,ETHUSD,Ethereum / U.S. dollar
+";
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("BTCUSD", "BTCUSD"), 0);
+        assert_eq!(edit_distance("BTCUDS", "BTCUSD"), 2);
+        assert_eq!(edit_distance("ETHUSD", "ETHUSDT"), 1);
+        assert_eq!(edit_distance("", "ABC"), 3);
+    }
+
+    #[test]
+    fn test_load_formats() {
+        let index = SymbolIndex::from_csv_str(CSV).unwrap();
+        assert_eq!(index.len(), 3);
+        let json = r#"[{"exchange": "binance", "ticker": "btcusdt", "description": "Bitcoin / TetherUS"}]"#;
+        let index = SymbolIndex::from_json_str(json).unwrap();
+        assert!(index.contains(&"BINANCE:BTCUSDT".parse().unwrap()));
+        assert!(SymbolIndex::from_csv_str("BINANCE").is_err());
+        assert!(SymbolIndex::from_json_str(r#"[{"ticker": "BTC"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_contains_and_suggest() {
+        let index = SymbolIndex::from_csv_str(CSV).unwrap();
+        assert!(index.contains(&"btcusd".parse().unwrap()));
+        assert!(!index.contains(&"coinbase:btcusd".parse().unwrap()));
+        let suggestions = index.suggest(&"btcusx".parse().unwrap(), 3);
+        assert_eq!(suggestions[0].symbol.to_string(), "BITSTAMP:BTCUSD");
+        assert!(index.suggest(&"xyz".parse().unwrap(), 3).is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let index = SymbolIndex::from_csv_str(CSV).unwrap();
+        let results: Vec<String> = index
+            .search("btc", 10)
+            .iter()
+            .map(|entry| entry.symbol.to_string())
+            .collect();
+        assert_eq!(results, vec!["BITSTAMP:BTCUSD", "BINANCE:BTCUSDT"]);
+        assert_eq!(index.search("ethereum", 10)[0].symbol.to_string(), "COINBASE:ETHUSD");
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::bot::ShipChat;
+use crate::chart_request::{Symbol, SymbolError};
+
+/// Turns what users type into the symbol that gets charted.
+///
+/// A symbol with an explicit exchange (`binance:btcusdt`) is used as is.
+/// Otherwise an alias (`btc` → `BITSTAMP:BTCUSD`) wins, then the chat's
+/// default exchange, then the global default exchange. If none apply the
+/// bare ticker is passed on and TradingView picks the exchange.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// Aliases keyed by their lowercase name.
+    pub aliases: HashMap<String, Symbol>,
+    pub default_exchange: Option<String>,
+    /// Default exchanges keyed by `ShipChat::key`.
+    pub chat_exchanges: HashMap<String, String>,
+}
+
+impl SymbolTable {
+    pub fn resolve(&self, input: &str, chat: &ShipChat) -> Result<Symbol, SymbolError> {
+        if let Some(symbol) = self.aliases.get(&input.to_lowercase()) {
+            return Ok(symbol.clone());
+        }
+        let mut symbol: Symbol = input.parse()?;
+        if symbol.exchange.is_none() {
+            symbol.exchange = self
+                .chat_exchanges
+                .get(&chat.key())
+                .or(self.default_exchange.as_ref())
+                .cloned();
+        }
+        Ok(symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat;
+
+    fn table() -> SymbolTable {
+        let mut table = SymbolTable::default();
+        table
+            .aliases
+            .insert("btc".to_string(), "BITSTAMP:BTCUSD".parse().unwrap());
+        table.default_exchange = Some("COINBASE".to_string());
+        table
+            .chat_exchanges
+            .insert("~zod/binance-chat".to_string(), "BINANCE".to_string());
+        table
+    }
+
+    #[test]
+    fn test_resolution_order() {
+        let table = table();
+        let resolve = |input: &str, chat_name: &str| table.resolve(input, &chat(chat_name)).unwrap().to_string();
+        assert_eq!(resolve("BTC", "binance-chat"), "BITSTAMP:BTCUSD");
+        assert_eq!(resolve("kraken:ethusd", "binance-chat"), "KRAKEN:ETHUSD");
+        assert_eq!(resolve("ethusdt", "binance-chat"), "BINANCE:ETHUSDT");
+        assert_eq!(resolve("ethusd", "other-chat"), "COINBASE:ETHUSD");
+        assert!(table.resolve("eth&usd", &chat("other-chat")).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Fixtures shared by the tests of every module.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::bot::ShipChat;
+
+/// The chat `name` on `~zod`.
+pub fn chat(name: &str) -> ShipChat {
+    ShipChat {
+        ship_name: "~zod".to_string(),
+        chat_name: name.to_string(),
+    }
+}
+
+/// A directory of its own for a test, deleted when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let unique = format!("urbit_bot_{}_{}_{}", name, process::id(), CREATED.fetch_add(1, Ordering::SeqCst));
+        let dir = env::temp_dir().join(unique);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
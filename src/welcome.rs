@@ -0,0 +1,77 @@
+use urbit_http_api::Message;
+
+use crate::bot::ShipChat;
+use crate::state_store::{StateStore, StoreError};
+
+/// Posts the welcome message from the config in chats the bot joins, once
+/// per chat. The chats already welcomed, or already joined when the bot
+/// first saw them, are kept in the state database, so rejoining a chat or
+/// restarting the bot does not repeat it.
+pub struct Welcome {
+    /// The message with `{bot}`, `{chat}` and `{group}` placeholders, if any.
+    template: Option<String>,
+    welcomed: sled::Tree,
+}
+
+impl Welcome {
+    pub fn new(template: Option<String>, store: &StateStore) -> Result<Self, StoreError> {
+        Ok(Welcome {
+            template,
+            welcomed: store.tree("welcomed_chats")?,
+        })
+    }
+
+    /// Never welcome `chat`, e.g. because the bot was in it before it
+    /// started keeping track.
+    pub fn mark_welcomed(&self, chat: &ShipChat) {
+        if let Err(e) = self.welcomed.insert(chat.key(), &b""[..]) {
+            println!("Failed to record the welcome in {}: {}", chat.key(), e);
+        }
+    }
+
+    /// The welcome for `chat` of `group`, posted by `bot`, or None if the chat
+    /// was welcomed before or there is no welcome message. Marks the chat as
+    /// welcomed.
+    pub fn message_for(&self, chat: &ShipChat, group: Option<&str>, bot: &str) -> Option<Message> {
+        let template = self.template.as_ref()?;
+        match self.welcomed.compare_and_swap(chat.key(), None as Option<&[u8]>, Some(&b""[..])) {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return None,
+            Err(e) => {
+                println!("Failed to record the welcome in {}: {}", chat.key(), e);
+                return None;
+            }
+        }
+        let text = template
+            .replace("{bot}", &format!("~{}", bot.trim_start_matches('~')))
+            .replace("{chat}", &chat.chat_name)
+            .replace("{group}", group.unwrap_or(&chat.key()));
+        Some(Message::new().add_text(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_each_chat_is_welcomed_once() {
+        let store = StateStore::temporary();
+        let template = "Hi {group}, I'm {bot}. Try `help` in {chat}.".to_string();
+        let welcome = Welcome::new(Some(template), &store).unwrap();
+        let chat = testing::chat("chat-1");
+
+        let message = welcome.message_for(&chat, Some("~zod/traders"), "nec").unwrap();
+        assert_eq!(message.to_formatted_string().trim(), "Hi ~zod/traders, I'm ~nec. Try `help` in chat-1.");
+        assert!(welcome.message_for(&chat, Some("~zod/traders"), "nec").is_none());
+
+        let silent = Welcome::new(None, &store).unwrap();
+        let other = testing::chat("chat-2");
+        assert!(silent.message_for(&other, None, "nec").is_none());
+
+        let joined_before = testing::chat("chat-3");
+        welcome.mark_welcomed(&joined_before);
+        assert!(welcome.message_for(&joined_before, None, "nec").is_none());
+    }
+}
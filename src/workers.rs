@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::bot::ShipChat;
+use urbit_http_api::{AuthoredMessage, Message};
+
+/// Turns a chat message into the bot's reply, if it has one.
+pub type Responder = fn(&ShipChat, AuthoredMessage) -> Option<Message>;
+
+/// Settings for the threads that run chat commands.
+#[derive(Clone, Debug)]
+pub struct WorkerPoolConfig {
+    /// Commands handled at the same time, across all chats.
+    pub threads: usize,
+    /// Commands a single chat may have waiting before new ones are turned away.
+    pub queue_per_chat: usize,
+}
+
+/// What a worker made of the message at `index` in `chat`: the reply to
+/// post there, if there is one.
+pub struct Reply {
+    pub chat: ShipChat,
+    pub index: String,
+    pub message: Option<Message>,
+}
+
+/// The chat already has as many commands waiting as it may queue.
+#[derive(Debug)]
+pub struct QueueFull(pub usize);
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} commands are already waiting in this chat", self.0)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+#[derive(Default)]
+struct Queues {
+    /// Messages waiting to be handled, per chat.
+    pending: HashMap<ShipChat, VecDeque<AuthoredMessage>>,
+    /// Chats waiting for a worker, in the order they get one.
+    ready: VecDeque<ShipChat>,
+    /// Chats that are in `ready` or being handled by a worker. A chat is only
+    /// ever handled by one worker at a time, which keeps its replies in order.
+    scheduled: HashSet<ShipChat>,
+    closed: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    work_available: Condvar,
+    respond: Responder,
+    replies: UnboundedSender<Reply>,
+}
+
+/// Runs chat commands on a fixed set of threads, so a slow command in one
+/// chat does not hold up the others. Each chat's commands run one after
+/// another, so its replies come back in the order the commands were sent.
+pub struct WorkerPool {
+    config: WorkerPoolConfig,
+    shared: Arc<Shared>,
+    replies: UnboundedReceiver<Reply>,
+}
+
+impl WorkerPool {
+    /// Start `config.threads` workers that answer messages with `respond`.
+    pub fn new(config: WorkerPoolConfig, respond: Responder) -> Self {
+        let (sender, replies) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            work_available: Condvar::new(),
+            respond,
+            replies: sender,
+        });
+        for _ in 0..config.threads.max(1) {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || Self::work(&shared));
+        }
+        WorkerPool {
+            config,
+            shared,
+            replies,
+        }
+    }
+
+    /// Queue `message` from `chat` to be handled by a worker.
+    pub fn submit(&self, chat: ShipChat, message: AuthoredMessage) -> Result<(), QueueFull> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        let pending = queues.pending.entry(chat.clone()).or_default();
+        if pending.len() >= self.config.queue_per_chat {
+            return Err(QueueFull(pending.len()));
+        }
+        pending.push_back(message);
+        if queues.scheduled.insert(chat.clone()) {
+            queues.ready.push_back(chat);
+            self.shared.work_available.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Drop the commands from `chat` that no worker has started on yet,
+    /// returning how many there were.
+    pub fn cancel(&self, chat: &ShipChat) -> usize {
+        let mut queues = self.shared.queues.lock().unwrap();
+        queues.pending.remove(chat).map_or(0, |pending| pending.len())
+    }
+
+    /// Wait for the next reply a worker finishes.
+    pub async fn next_reply(&mut self) -> Option<Reply> {
+        self.replies.recv().await
+    }
+
+    fn work(shared: &Shared) {
+        loop {
+            let (chat, message) = {
+                let mut queues = shared.queues.lock().unwrap();
+                let chat = loop {
+                    if let Some(chat) = queues.ready.pop_front() {
+                        break chat;
+                    }
+                    if queues.closed {
+                        return;
+                    }
+                    queues = shared.work_available.wait(queues).unwrap();
+                };
+                let message = queues.pending.get_mut(&chat).and_then(|pending| pending.pop_front());
+                (chat, message)
+            };
+
+            if let Some(message) = message {
+                let index = message.index.clone();
+                let reply = (shared.respond)(&chat, message);
+                match reply {
+                    Some(_) => println!("Replied to message."),
+                    None => println!("Message ignored."),
+                }
+                // Ignored messages are reported too, so their claim is let go
+                let reply = Reply {
+                    chat: chat.clone(),
+                    index,
+                    message: reply,
+                };
+                if shared.replies.send(reply).is_err() {
+                    return;
+                }
+            }
+
+            // Hand the chat to the back of the line if it has more waiting,
+            // so a busy chat takes turns with the others
+            let mut queues = shared.queues.lock().unwrap();
+            if queues.pending.get(&chat).is_none_or(|pending| pending.is_empty()) {
+                queues.pending.remove(&chat);
+                queues.scheduled.remove(&chat);
+            } else {
+                queues.ready.push_back(chat);
+                shared.work_available.notify_one();
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().closed = true;
+        self.shared.work_available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn message(text: &str) -> AuthoredMessage {
+        AuthoredMessage::new("~bus", &Message::new().add_text(text), "", "")
+    }
+
+    // Echoes the message back, taking longer for messages that start with "slow"
+    fn echo(_: &ShipChat, message: AuthoredMessage) -> Option<Message> {
+        let text = message.contents.to_formatted_string();
+        if text.trim().starts_with("slow") {
+            thread::sleep(Duration::from_millis(200));
+        }
+        Some(Message::new().add_text(text.trim()))
+    }
+
+    async fn collect(pool: &mut WorkerPool, count: usize) -> Vec<(String, String)> {
+        let mut replies = vec![];
+        while replies.len() < count {
+            match timeout(Duration::from_secs(5), pool.next_reply()).await {
+                Ok(Some(reply)) => {
+                    let text = reply.message.unwrap().to_formatted_string().trim().to_string();
+                    replies.push((reply.chat.chat_name, text));
+                }
+                _ => break,
+            }
+        }
+        replies
+    }
+
+    #[tokio::test]
+    async fn test_replies_stay_in_order_per_chat() {
+        let config = WorkerPoolConfig {
+            threads: 4,
+            queue_per_chat: 10,
+        };
+        let mut pool = WorkerPool::new(config, echo);
+        pool.submit(chat("a"), message("slow 1")).unwrap();
+        pool.submit(chat("a"), message("2")).unwrap();
+        pool.submit(chat("b"), message("3")).unwrap();
+
+        let replies = collect(&mut pool, 3).await;
+        // The slow command in `a` does not hold up `b`, but does hold up `a`
+        assert_eq!(replies[0], ("b".to_string(), "3".to_string()));
+        let in_a: Vec<_> = replies.iter().filter(|(chat, _)| chat == "a").map(|(_, text)| text.as_str()).collect();
+        assert_eq!(in_a, vec!["slow 1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_is_rejected() {
+        let config = WorkerPoolConfig {
+            threads: 1,
+            queue_per_chat: 1,
+        };
+        let mut pool = WorkerPool::new(config, echo);
+        pool.submit(chat("a"), message("slow 1")).unwrap();
+        // Wait for the worker to pick up the first command
+        thread::sleep(Duration::from_millis(50));
+        pool.submit(chat("a"), message("2")).unwrap();
+        assert!(pool.submit(chat("a"), message("3")).is_err());
+        // Other chats have their own queue
+        pool.submit(chat("b"), message("4")).unwrap();
+        assert_eq!(collect(&mut pool, 3).await.len(), 3);
+
+        // Cancelled commands are never answered
+        pool.submit(chat("a"), message("slow 5")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        pool.submit(chat("a"), message("6")).unwrap();
+        assert_eq!(pool.cancel(&chat("a")), 1);
+        assert_eq!(collect(&mut pool, 1).await, vec![("a".to_string(), "slow 5".to_string())]);
+        assert!(timeout(Duration::from_millis(300), pool.next_reply()).await.is_err());
+    }
+}